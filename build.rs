@@ -0,0 +1,42 @@
+use std::fs;
+use std::process::Command;
+
+/// Records the git commit and the versions of key dependencies for `--version`.
+fn main() {
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-changed=Cargo.lock");
+
+    let commit = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=PLANT_CAM_GIT_COMMIT={}", commit);
+
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    for (package, variable) in [("nokhwa", "PLANT_CAM_NOKHWA_VERSION"), ("image", "PLANT_CAM_IMAGE_VERSION")] {
+        println!("cargo:rustc-env={}={}", variable, locked_version(&lock, package));
+    }
+}
+
+/// The versions of `package` in `Cargo.lock`, comma separated if there are several.
+fn locked_version(lock: &str, package: &str) -> String {
+    let name_line = format!("name = \"{}\"", package);
+    let mut lines = lock.lines();
+    let mut versions = Vec::new();
+    while let Some(line) = lines.next() {
+        if line == name_line {
+            if let Some(version) = lines.next().and_then(|line| line.strip_prefix("version = ")) {
+                versions.push(version.trim_matches('"').to_string());
+            }
+        }
+    }
+    if versions.is_empty() {
+        return "unknown".to_string();
+    }
+    versions.join(", ")
+}
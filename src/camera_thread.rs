@@ -0,0 +1,115 @@
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::time::Duration;
+use anyhow::{anyhow, Context, Result};
+use image::RgbImage;
+use log::info;
+use nokhwa::{CameraInfo, Resolution};
+use tokio::sync::oneshot;
+use crate::{open_camera, rtsp, CameraConfig, Config};
+
+type FrameReply = oneshot::Sender<Result<RgbImage>>;
+
+/// A camera owned by a dedicated thread, since nokhwa cameras can't move between threads.
+/// A frame grab that never returns then only blocks that thread, and the handle can be dropped.
+pub struct CameraHandle {
+    info: CameraInfo,
+    resolution: Resolution,
+    frame_rate: u32,
+    requests: mpsc::Sender<FrameReply>,
+}
+
+impl CameraHandle {
+    /// Opens the camera on a new thread, which then serves frames until the handle is dropped.
+    pub async fn open(config: Arc<Config>, camera_config: CameraConfig, cameras: Vec<CameraInfo>) -> Result<CameraHandle> {
+        if camera_config.source_type.eq_ignore_ascii_case("rtsp") {
+            return CameraHandle::open_rtsp(camera_config.rtsp_url, Duration::from_secs(config.capture_timeout_secs)).await;
+        }
+        let (opened_sender, opened) = oneshot::channel();
+        std::thread::spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread().enable_time().build() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    opened_sender.send(Err(anyhow!(e).context("Could not start camera thread"))).ok();
+                    return;
+                }
+            };
+            let mut camera = match runtime.block_on(open_camera(&config, &camera_config, &cameras)) {
+                Ok(camera) => camera,
+                Err(e) => {
+                    opened_sender.send(Err(e)).ok();
+                    return;
+                }
+            };
+            let (requests, receiver) = mpsc::channel::<FrameReply>();
+            let handle = CameraHandle {
+                info: camera.info().clone(),
+                resolution: camera.resolution(),
+                frame_rate: camera.frame_rate(),
+                requests,
+            };
+            if opened_sender.send(Ok(handle)).is_err() {
+                return;
+            }
+            for reply in receiver {
+                reply.send(camera.frame().map_err(anyhow::Error::from)).ok();
+            }
+        });
+        opened.await.context("Camera thread exited while opening the camera")?
+    }
+
+    /// Connects to an RTSP stream, reading one frame per request through ffmpeg. A first frame is
+    /// read up front to check the stream works and learn its resolution. Each read gives up after `timeout`.
+    async fn open_rtsp(url: String, timeout: Duration) -> Result<CameraHandle> {
+        let (opened_sender, opened) = oneshot::channel();
+        std::thread::spawn(move || {
+            let resolution = match rtsp::read_frame(&url, timeout) {
+                Ok(frame) => {
+                    info!("Connected to RTSP stream, frames are {}x{}.", frame.width(), frame.height());
+                    Resolution::new(frame.width(), frame.height())
+                }
+                Err(e) => {
+                    opened_sender.send(Err(e.context("Could not read from the RTSP stream"))).ok();
+                    return;
+                }
+            };
+            let (requests, receiver) = mpsc::channel::<FrameReply>();
+            let handle = CameraHandle {
+                // The URL is left out, as it often holds credentials and the info ends up in EXIF.
+                info: CameraInfo::new("RTSP camera".to_string(), "RTSP stream".to_string(), "".to_string(), 0),
+                resolution,
+                frame_rate: 0,
+                requests,
+            };
+            if opened_sender.send(Ok(handle)).is_err() {
+                return;
+            }
+            for reply in receiver {
+                reply.send(rtsp::read_frame(&url, timeout)).ok();
+            }
+        });
+        opened.await.context("Camera thread exited while connecting to the RTSP stream")?
+    }
+
+    pub fn info(&self) -> &CameraInfo {
+        &self.info
+    }
+
+    pub fn index(&self) -> usize {
+        self.info.index()
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    pub fn frame_rate(&self) -> u32 {
+        self.frame_rate
+    }
+
+    pub async fn frame(&self) -> Result<RgbImage> {
+        let (reply, frame) = oneshot::channel();
+        self.requests.send(reply).map_err(|_| anyhow!("Camera thread has exited"))?;
+        frame.await.context("Camera thread exited without a frame")?.context("Failed to get frame")
+    }
+}
@@ -0,0 +1,41 @@
+use std::path::PathBuf;
+use anyhow::{bail, Result};
+use image::imageops::{overlay, thumbnail};
+use image::RgbImage;
+use log::{info, warn};
+
+/// Tiles `frames`, in order, into a roughly square grid of `tile_width` wide cells. Cells take
+/// the first frame's aspect ratio; frames that don't share it are fitted inside and centered.
+/// Frames that can't be read are skipped.
+pub fn make_collage(frames: &[PathBuf], tile_width: u32) -> Result<RgbImage> {
+    let images: Vec<RgbImage> = frames
+        .iter()
+        .filter_map(|path| match image::open(path) {
+            Ok(image) => Some(image.into_rgb8()),
+            Err(e) => {
+                warn!("Leaving {:?} out of the collage: {}.", path, e);
+                None
+            }
+        })
+        .collect();
+    let Some(first) = images.first() else {
+        bail!("None of the {} captures could be read", frames.len());
+    };
+
+    let columns = (images.len() as f64).sqrt().ceil() as u32;
+    let rows = (images.len() as u32).div_ceil(columns);
+    let tile_height = ((first.height() as u64 * tile_width as u64) / first.width() as u64).max(1) as u32;
+    let mut collage = RgbImage::new(columns * tile_width, rows * tile_height);
+    for (i, image) in images.iter().enumerate() {
+        let scale = (tile_width as f64 / image.width() as f64).min(tile_height as f64 / image.height() as f64);
+        let width = ((image.width() as f64 * scale).round() as u32).clamp(1, tile_width);
+        let height = ((image.height() as f64 * scale).round() as u32).clamp(1, tile_height);
+        let tile = thumbnail(image, width, height);
+        let (column, row) = (i as u32 % columns, i as u32 / columns);
+        let x = column * tile_width + (tile_width - width) / 2;
+        let y = row * tile_height + (tile_height - height) / 2;
+        overlay(&mut collage, &tile, x, y);
+    }
+    info!("Made a {}x{} collage of {} captures.", columns, rows, images.len());
+    Ok(collage)
+}
@@ -0,0 +1,79 @@
+use log::info;
+use image::RgbImage;
+
+/// Gray-world white balance: scales each channel so its mean matches the mean of all three.
+pub fn gray_world(mut image: RgbImage) -> RgbImage {
+    let mut sums = [0u64; 3];
+    for pixel in image.pixels() {
+        for (sum, value) in sums.iter_mut().zip(pixel.0.iter()) {
+            *sum += *value as u64;
+        }
+    }
+    let pixel_count = (image.width() as u64 * image.height() as u64).max(1) as f64;
+    let means = sums.map(|sum| sum as f64 / pixel_count);
+    let gray = means.iter().sum::<f64>() / 3.0;
+    let gains = means.map(|mean| if mean > 0.0 { gray / mean } else { 1.0 });
+    info!("Applying white balance gains R {:.3}, G {:.3}, B {:.3}.", gains[0], gains[1], gains[2]);
+
+    for pixel in image.pixels_mut() {
+        for (value, gain) in pixel.0.iter_mut().zip(gains.iter()) {
+            *value = (*value as f64 * gain).round().min(255.0) as u8;
+        }
+    }
+    image
+}
+
+/// Applies `value^gamma` to every channel through a lookup table; below 1.0 brightens midtones.
+pub fn gamma(mut image: RgbImage, gamma: f64) -> RgbImage {
+    info!("Applying gamma {:.2}.", gamma);
+    let mut table = [0u8; 256];
+    for (value, entry) in table.iter_mut().enumerate() {
+        *entry = ((value as f64 / 255.0).powf(gamma) * 255.0).round() as u8;
+    }
+    for value in image.iter_mut() {
+        *value = table[*value as usize];
+    }
+    image
+}
+
+/// Logs min, max, mean and the 5th, 50th and 95th percentiles of each channel, to help tune
+/// exposure and white balance. Leaves the image untouched.
+pub fn log_histogram(image: &RgbImage) {
+    let mut histograms = [[0u64; 256]; 3];
+    for pixel in image.pixels() {
+        for (histogram, value) in histograms.iter_mut().zip(pixel.0.iter()) {
+            histogram[*value as usize] += 1;
+        }
+    }
+    let pixel_count = image.width() as u64 * image.height() as u64;
+    if pixel_count == 0 {
+        return;
+    }
+    let summaries: Vec<String> = ["R", "G", "B"]
+        .iter()
+        .zip(histograms.iter())
+        .map(|(channel, histogram)| {
+            let values = || histogram.iter().enumerate().filter(|(_, count)| **count > 0).map(|(value, _)| value);
+            let min = values().next().unwrap_or(0);
+            let max = values().next_back().unwrap_or(0);
+            let total: u64 = histogram.iter().enumerate().map(|(value, count)| value as u64 * count).sum();
+            let mean = total as f64 / pixel_count as f64;
+            let percentile = |percent: u64| {
+                let target = (pixel_count * percent).div_ceil(100).max(1);
+                let mut seen = 0;
+                histogram
+                    .iter()
+                    .position(|count| {
+                        seen += count;
+                        seen >= target
+                    })
+                    .unwrap_or(255)
+            };
+            format!(
+                "{} min {} p5 {} p50 {} p95 {} max {} mean {:.1}",
+                channel, min, percentile(5), percentile(50), percentile(95), max, mean,
+            )
+        })
+        .collect();
+    info!("Histogram: {}.", summaries.join(", "));
+}
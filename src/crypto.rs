@@ -0,0 +1,57 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+
+/// AES-GCM's standard 96-bit nonce, stored in front of the ciphertext.
+const NONCE_LENGTH: usize = 12;
+
+/// Parses `encryption_key`, 64 hex digits making a 256-bit key.
+pub fn parse_key(key: &str) -> Result<[u8; 32]> {
+    if key.len() != 64 || !key.is_ascii() {
+        bail!("encryption_key must be 64 hex digits, got {} characters", key.len());
+    }
+    let mut bytes = [0u8; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&key[i * 2..i * 2 + 2], 16).context("encryption_key must be 64 hex digits")?;
+    }
+    Ok(bytes)
+}
+
+/// Encrypts `plaintext` with AES-256-GCM under a fresh random nonce, returning the nonce followed by the ciphertext.
+pub fn encrypt(key: &[u8; 32], plaintext: &[u8]) -> Result<Vec<u8>> {
+    let cipher = Aes256Gcm::new(key.into());
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = cipher.encrypt(&nonce, plaintext).map_err(|_| anyhow!("Failed to encrypt"))?;
+    let mut output = Vec::with_capacity(NONCE_LENGTH + ciphertext.len());
+    output.extend_from_slice(&nonce);
+    output.extend_from_slice(&ciphertext);
+    Ok(output)
+}
+
+/// Reverses `encrypt`, failing if the data was tampered with or the key is wrong.
+pub fn decrypt(key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() < NONCE_LENGTH {
+        bail!("Too short to be encrypted by plant_cam");
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LENGTH);
+    let cipher = Aes256Gcm::new(key.into());
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Failed to decrypt, the key is wrong or the file is corrupt"))
+}
+
+/// Decrypts a downloaded `file`, writing it next to it without its `.enc` extension.
+pub fn decrypt_file(key: &[u8; 32], file: &Path) -> Result<PathBuf> {
+    let output = match file.extension() {
+        Some(extension) if extension == "enc" => file.with_extension(""),
+        _ => PathBuf::from(format!("{}.dec", file.display())),
+    };
+    let data = fs::read(file).with_context(|| format!("Failed to read {:?}", file))?;
+    let plaintext = decrypt(key, &data).with_context(|| format!("Could not decrypt {:?}", file))?;
+    fs::write(&output, plaintext).with_context(|| format!("Failed to write {:?}", output))?;
+    info!("Decrypted {:?} to {:?}.", file, output);
+    Ok(output)
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+
+/// What went wrong, so scripts can tell failures apart by exit code. Each variant keeps the
+/// underlying error and its context chain.
+#[derive(Debug)]
+pub enum PlantCamError {
+    /// Bad arguments, config file or destination settings.
+    Config(anyhow::Error),
+    /// The camera could not be found, opened or started.
+    CameraInit(anyhow::Error),
+    /// An opened camera failed to produce a usable frame.
+    Capture(anyhow::Error),
+    /// A frame could not be encoded, or a timelapse could not be made.
+    Encode(anyhow::Error),
+    /// A capture could not be saved or uploaded.
+    Upload(anyhow::Error),
+}
+
+impl PlantCamError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            PlantCamError::Config(_) => 2,
+            PlantCamError::CameraInit(_) | PlantCamError::Capture(_) => 3,
+            PlantCamError::Upload(_) => 4,
+            PlantCamError::Encode(_) => 5,
+        }
+    }
+
+    /// Wraps the underlying error in `context`, keeping the variant.
+    pub fn context(self, context: String) -> PlantCamError {
+        match self {
+            PlantCamError::Config(e) => PlantCamError::Config(e.context(context)),
+            PlantCamError::CameraInit(e) => PlantCamError::CameraInit(e.context(context)),
+            PlantCamError::Capture(e) => PlantCamError::Capture(e.context(context)),
+            PlantCamError::Encode(e) => PlantCamError::Encode(e.context(context)),
+            PlantCamError::Upload(e) => PlantCamError::Upload(e.context(context)),
+        }
+    }
+
+    fn inner(&self) -> &anyhow::Error {
+        match self {
+            PlantCamError::Config(e)
+            | PlantCamError::CameraInit(e)
+            | PlantCamError::Capture(e)
+            | PlantCamError::Encode(e)
+            | PlantCamError::Upload(e) => e,
+        }
+    }
+}
+
+impl fmt::Display for PlantCamError {
+    /// Shows the underlying message, or with `{:#}` its whole context chain.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            write!(f, "{:#}", self.inner())
+        } else {
+            write!(f, "{}", self.inner())
+        }
+    }
+}
+
+impl std::error::Error for PlantCamError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner().source()
+    }
+}
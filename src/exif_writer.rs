@@ -0,0 +1,58 @@
+use std::io::Cursor;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, FixedOffset};
+use exif::experimental::Writer;
+use exif::{Field, In, Tag, Value};
+use nokhwa::CameraInfo;
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const JPEG_APP0: [u8; 2] = [0xFF, 0xE0];
+const JPEG_APP1: [u8; 2] = [0xFF, 0xE1];
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Returns `jpeg` with an APP1 segment holding the capture time and camera name.
+pub fn embed_exif(jpeg: &[u8], camera: &CameraInfo, captured_at: DateTime<FixedOffset>) -> Result<Vec<u8>> {
+    if !jpeg.starts_with(&JPEG_SOI) {
+        bail!("Cannot embed EXIF, picture is not a JPEG");
+    }
+
+    let ascii = |text: String| Value::Ascii(vec![text.into_bytes()]);
+    let fields = [
+        Field { tag: Tag::Make, ifd_num: In::PRIMARY, value: ascii(camera.description()) },
+        Field { tag: Tag::Model, ifd_num: In::PRIMARY, value: ascii(camera.human_name()) },
+        Field {
+            tag: Tag::DateTimeOriginal,
+            ifd_num: In::PRIMARY,
+            value: ascii(captured_at.format("%Y:%m:%d %H:%M:%S").to_string()),
+        },
+    ];
+    let mut writer = Writer::new();
+    for field in fields.iter() {
+        writer.push_field(field);
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, false).context("Failed to encode EXIF")?;
+    let tiff = tiff.into_inner();
+
+    Ok(insert_jpeg_segment(jpeg, JPEG_APP1, &[EXIF_HEADER, &tiff]))
+}
+
+/// Returns `jpeg` with a `marker` segment made of `parts` inserted right after the start of image,
+/// or after a leading JFIF APP0 segment, which viewers expect to come first.
+pub fn insert_jpeg_segment(jpeg: &[u8], marker: [u8; 2], parts: &[&[u8]]) -> Vec<u8> {
+    let mut insert_at = JPEG_SOI.len();
+    if jpeg[insert_at..].starts_with(&JPEG_APP0) && jpeg.len() >= insert_at + 4 {
+        insert_at += 2 + u16::from_be_bytes([jpeg[insert_at + 2], jpeg[insert_at + 3]]) as usize;
+    }
+
+    let segment_length = (2 + parts.iter().map(|part| part.len()).sum::<usize>()) as u16;
+    let mut output = Vec::with_capacity(jpeg.len() + segment_length as usize + 2);
+    output.extend_from_slice(&jpeg[..insert_at]);
+    output.extend_from_slice(&marker);
+    output.extend_from_slice(&segment_length.to_be_bytes());
+    for part in parts {
+        output.extend_from_slice(part);
+    }
+    output.extend_from_slice(&jpeg[insert_at..]);
+    output
+}
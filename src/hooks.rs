@@ -0,0 +1,30 @@
+use std::time::Duration;
+use log::{debug, warn};
+use tokio::process::Command;
+
+/// Runs `command` through the shell with `env` set, killing it after `timeout_secs` unless that is 0.
+/// Failures are only logged, so a broken hook never fails the capture.
+pub async fn run_hook(name: &str, command: &str, timeout_secs: u64, env: &[(&str, &str)]) {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command).envs(env.iter().copied()).kill_on_drop(true);
+    debug!("Running {} hook {:?}.", name, command);
+    let status = if timeout_secs == 0 {
+        Ok(shell.status().await)
+    } else {
+        tokio::time::timeout(Duration::from_secs(timeout_secs), shell.status()).await
+    };
+    match status {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => warn!("The {} hook {:?} exited with {}.", name, command, status),
+        Ok(Err(e)) => warn!("Could not run the {} hook {:?}: {}.", name, command, e),
+        Err(_) => warn!("The {} hook {:?} timed out after {} seconds and was killed.", name, command, timeout_secs),
+    }
+}
@@ -0,0 +1,43 @@
+use anyhow::{bail, Result};
+use crate::exif_writer::insert_jpeg_segment;
+
+/// A minimal ICC v2 sRGB display profile: D50-adapted sRGB primaries and the sRGB tone curve.
+static SRGB_PROFILE: &[u8] = include_bytes!("../assets/sRGB.icc");
+
+const JPEG_SOI: [u8; 2] = [0xFF, 0xD8];
+const JPEG_APP2: [u8; 2] = [0xFF, 0xE2];
+const ICC_HEADER: &[u8] = b"ICC_PROFILE\0";
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+/// Length, type, 13 bytes of data and CRC.
+const PNG_IHDR_LENGTH: usize = 25;
+
+/// Returns `jpeg` with an APP2 segment holding the sRGB profile, so viewers don't have to guess.
+pub fn embed_srgb_jpeg(jpeg: &[u8]) -> Result<Vec<u8>> {
+    if !jpeg.starts_with(&JPEG_SOI) {
+        bail!("Cannot embed the color profile, picture is not a JPEG");
+    }
+    // The profile fits in one segment, numbered 1 of 1.
+    Ok(insert_jpeg_segment(jpeg, JPEG_APP2, &[ICC_HEADER, &[1, 1], SRGB_PROFILE]))
+}
+
+/// Returns `png` with an iCCP chunk holding the sRGB profile, placed right after IHDR as required.
+pub fn embed_srgb_png(png: &[u8]) -> Result<Vec<u8>> {
+    let insert_at = PNG_SIGNATURE.len() + PNG_IHDR_LENGTH;
+    if !png.starts_with(&PNG_SIGNATURE) || png.len() < insert_at || &png[12..16] != b"IHDR" {
+        bail!("Cannot embed the color profile, picture is not a PNG");
+    }
+
+    // Profile name, compression method 0 (zlib), then the compressed profile.
+    let mut chunk = b"iCCP".to_vec();
+    chunk.extend_from_slice(b"sRGB\0\0");
+    chunk.extend_from_slice(&miniz_oxide::deflate::compress_to_vec_zlib(SRGB_PROFILE, 9));
+    let crc = crc32fast::hash(&chunk);
+
+    let mut output = Vec::with_capacity(png.len() + chunk.len() + 8);
+    output.extend_from_slice(&png[..insert_at]);
+    output.extend_from_slice(&((chunk.len() - 4) as u32).to_be_bytes());
+    output.extend_from_slice(&chunk);
+    output.extend_from_slice(&crc.to_be_bytes());
+    output.extend_from_slice(&png[insert_at..]);
+    Ok(output)
+}
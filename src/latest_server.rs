@@ -0,0 +1,96 @@
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use log::{info, error};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+
+/// The most recent capture's encoded bytes and content type.
+static LATEST: Mutex<Option<(Vec<u8>, &'static str)>> = Mutex::new(None);
+
+/// Up to the last `recent_buffer_size` captures, newest first.
+static RECENT: Mutex<VecDeque<(Vec<u8>, &'static str)>> = Mutex::new(VecDeque::new());
+
+pub fn set_latest(content: Vec<u8>, content_type: &'static str, recent_buffer_size: usize) {
+    if recent_buffer_size > 0 {
+        let mut recent = RECENT.lock().unwrap();
+        recent.push_front((content.clone(), content_type));
+        recent.truncate(recent_buffer_size);
+    }
+    *LATEST.lock().unwrap() = Some((content, content_type));
+}
+
+fn page(refresh_secs: u64, extension: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta http-equiv=\"refresh\" content=\"{}\">\n<title>Plant Cam</title>\n</head>\n\
+         <body style=\"margin: 0; background: #000\">\n<img src=\"/latest.{}\" style=\"max-width: 100%; max-height: 100vh\">\n</body>\n</html>\n",
+        refresh_secs, extension,
+    )
+}
+
+fn recent_page(refresh_secs: u64, extension: &str) -> String {
+    let images: String = (0..RECENT.lock().unwrap().len())
+        .map(|n| format!("<a href=\"/recent/{0}.{1}\"><img src=\"/recent/{0}.{1}\" style=\"width: 240px; margin: 4px\"></a>\n", n, extension))
+        .collect();
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta http-equiv=\"refresh\" content=\"{}\">\n<title>Plant Cam</title>\n</head>\n\
+         <body style=\"margin: 0; background: #000\">\n{}</body>\n</html>\n",
+        refresh_secs, images,
+    )
+}
+
+fn image_response(content: Option<(Vec<u8>, &'static str)>) -> hyper::http::Result<Response<Body>> {
+    match content {
+        Some((content, content_type)) => Response::builder()
+            .header("Content-Type", content_type)
+            .header("Cache-Control", "no-store")
+            .body(Body::from(content)),
+        None => Response::builder().status(StatusCode::SERVICE_UNAVAILABLE).body(Body::from("No capture yet.\n")),
+    }
+}
+
+/// The buffered capture at `path` such as `/recent/0.jpg`, where 0 is the newest.
+fn recent_image(path: &str, extension: &str) -> Option<hyper::http::Result<Response<Body>>> {
+    let n: usize = path.strip_prefix("/recent/")?.strip_suffix(extension)?.strip_suffix('.')?.parse().ok()?;
+    let content = RECENT.lock().unwrap().get(n).cloned()?;
+    Some(image_response(Some(content)))
+}
+
+/// `extension` is the output format's, so image paths match what they serve.
+async fn handle(request: Request<Body>, refresh_secs: u64, extension: &'static str) -> Result<Response<Body>, Infallible> {
+    let response = match request.uri().path() {
+        "/" => Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(page(refresh_secs, extension))),
+        path if path.strip_prefix("/latest.") == Some(extension) => image_response(LATEST.lock().unwrap().clone()),
+        "/recent" | "/recent/" => Response::builder()
+            .header("Content-Type", "text/html; charset=utf-8")
+            .body(Body::from(recent_page(refresh_secs, extension))),
+        path => recent_image(path, extension)
+            .unwrap_or_else(|| Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty())),
+    };
+    Ok(response.unwrap())
+}
+
+/// Serves the latest capture on `port` from a background task, with a page reloading every `refresh_secs`,
+/// and the recent captures under `/recent`. Images are served as `/latest.<extension>`.
+pub fn spawn_server(port: u16, refresh_secs: u64, extension: &'static str) {
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+    tokio::spawn(async move {
+        let service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |request| handle(request, refresh_secs, extension)))
+        });
+        let server = match Server::try_bind(&address) {
+            Ok(builder) => builder.serve(service),
+            Err(e) => {
+                error!("Could not start latest capture server on {}: {}.", address, e);
+                return;
+            }
+        };
+        info!("Serving the latest capture on http://{}/.", address);
+        if let Err(e) = server.await {
+            error!("Latest capture server stopped: {}.", e);
+        }
+    });
+}
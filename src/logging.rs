@@ -0,0 +1,109 @@
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::{Level, Log, Metadata, Record};
+use serde::Serialize;
+use simple_logger::SimpleLogger;
+
+static JSON: AtomicBool = AtomicBool::new(false);
+
+tokio::task_local! {
+    /// Identifies the capture a task is working on, and is added to each of its log lines.
+    static CAPTURE_ID: String;
+}
+
+/// Delegates to `simple_logger` for text output, or writes one JSON object per line.
+/// The format can be switched after startup, once the config has been read.
+struct Logger {
+    text: SimpleLogger,
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    timestamp: String,
+    level: &'a str,
+    target: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    capture_id: Option<String>,
+    message: String,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let capture_id = capture_id();
+        if !JSON.load(Ordering::Relaxed) {
+            match &capture_id {
+                Some(capture_id) => self.text.log(
+                    &Record::builder()
+                        .args(format_args!("[{}] {}", capture_id, record.args()))
+                        .metadata(record.metadata().clone())
+                        .module_path(record.module_path())
+                        .file(record.file())
+                        .line(record.line())
+                        .build(),
+                ),
+                None => self.text.log(record),
+            }
+            return;
+        }
+        let line = JsonRecord {
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            level: record.level().as_str(),
+            target: record.target(),
+            capture_id,
+            message: record.args().to_string(),
+        };
+        println!("{}", serde_json::to_string(&line).unwrap());
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init(level: Level) {
+    log::set_boxed_logger(Box::new(Logger { text: SimpleLogger::new() })).expect("Failed to initialise logger");
+    log::set_max_level(level.to_level_filter());
+}
+
+/// Sets the maximum level from a name such as `"info"` or `"debug"`.
+pub fn set_level(level: &str) -> Result<(), String> {
+    let level = Level::from_str(level.trim())
+        .map_err(|_| format!("Unknown log level {:?}, expected one of error, warn, info, debug or trace", level))?;
+    log::set_max_level(level.to_level_filter());
+    Ok(())
+}
+
+/// Switches between `"text"` and `"json"` output.
+pub fn set_format(format: &str) -> Result<(), String> {
+    match format.to_lowercase().as_str() {
+        "text" => JSON.store(false, Ordering::Relaxed),
+        "json" => JSON.store(true, Ordering::Relaxed),
+        other => return Err(format!("Unknown log format {:?}, expected \"text\" or \"json\"", other)),
+    }
+    Ok(())
+}
+
+/// A random version 4 UUID for tagging one capture.
+pub fn new_capture_id() -> String {
+    let mut value = fastrand::u128(..);
+    value = (value & !(0xF << 76)) | (0x4 << 76);
+    value = (value & !(0x3 << 62)) | (0x2 << 62);
+    let hex = format!("{:032x}", value);
+    format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+}
+
+/// Runs `future` with its log lines tagged with `capture_id`.
+pub async fn with_capture_id<F: Future>(capture_id: String, future: F) -> F::Output {
+    CAPTURE_ID.scope(capture_id, future).await
+}
+
+/// The ID of the capture the current task is working on, if any.
+pub fn capture_id() -> Option<String> {
+    CAPTURE_ID.try_with(|capture_id| capture_id.clone()).ok()
+}
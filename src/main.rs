@@ -1,149 +1,2922 @@
-use log::{info, warn, error};
+mod camera_thread;
+mod collage;
+mod color_correction;
+mod config_template;
+mod crypto;
+mod error;
+mod exif_writer;
+mod hooks;
+mod icc;
+mod latest_server;
+mod logging;
+mod metrics;
+mod overlay;
+mod rtsp;
+mod timelapse;
+mod upload;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{debug, info, warn, error};
 use serde::{Serialize, Deserialize};
-use nokhwa::{Camera, CameraInfo, CameraFormat, Resolution, FrameFormat};
-use std::path::{PathBuf};
+use nokhwa::{Camera, CameraInfo, CameraFormat, Resolution, FrameFormat, NokhwaError};
+use std::path::{Path, PathBuf};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
 use std::fs;
-use std::fs::File;
-use std::io::prelude::*;
-use image::ImageFormat;
-use image::imageops::crop_imm;
-use chrono::{Local};
-use s3::Region;
-use s3::bucket::Bucket;
-use awscreds::Credentials;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{watch, Semaphore};
+use tokio::task::{JoinError, JoinHandle};
+use image::{ColorType, Delay, DynamicImage, Frame, Rgb, RgbImage, RgbaImage};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::imageops::{crop_imm, flip_horizontal, flip_vertical, grayscale, overlay, resize, rotate180, rotate270, rotate90, thumbnail, unsharpen, FilterType};
+use imageproc::drawing::draw_hollow_rect_mut;
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use imageproc::rect::Rect;
+use ravif::{Encoder as AvifEncoder, Img, RGB8};
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveTime, Offset, TimeZone};
+use chrono_tz::Tz;
+use chrono::format::{Item, StrftimeItems};
+use camera_thread::CameraHandle;
+use error::PlantCamError;
+use exif_writer::embed_exif;
+use overlay::{draw_text_overlay, draw_watermark, load_watermark, OverlayPosition};
+use upload::{get_destinations, Destination};
+
+#[tokio::main]
+async fn main() {
+    logging::init(log::Level::Info);
+    if let Err(e) = run().await {
+        error!("{:#}, exiting...", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+async fn run() -> Result<(), PlantCamError> {
+    let args = parse_args().map_err(PlantCamError::Config)?;
+    if let Some(log_level) = args.log_level {
+        logging::set_level(log_level).map_err(|e| PlantCamError::Config(anyhow!(e)))?;
+    }
+    if args.version {
+        print_version();
+        return Ok(());
+    }
+    if args.list_cameras {
+        return list_cameras().map_err(PlantCamError::CameraInit);
+    }
+    if args.init_config {
+        return init_config(&args.config_path, args.force).map_err(PlantCamError::Config);
+    }
+    let mut config = get_config(&args.config_path, args.log_level).map_err(PlantCamError::Config)?;
+    if args.once && config.capture_interval_secs != 0 {
+        info!("Capturing once, ignoring capture_interval_secs {}.", config.capture_interval_secs);
+        config.capture_interval_secs = 0;
+    }
+    // These modes never upload, so they shouldn't need working credentials.
+    if args.dry_run || args.preview_crop || args.make_timelapse.is_some() || args.decrypt.is_some() {
+        config.upload_enabled = false;
+    }
+    let config = Arc::new(config);
+    if let Err(problems) = config.validate() {
+        for problem in &problems {
+            error!("Invalid config: {}.", problem);
+        }
+        return Err(PlantCamError::Config(anyhow!("Found {} problem(s) in {:?}", problems.len(), args.config_path)));
+    }
+    if let Some(output) = &args.make_timelapse {
+        let format = get_output_format(&config).map_err(PlantCamError::Config)?;
+        let frames = list_captures(&config.output_dir, &config.output_prefix, format);
+        return timelapse::make_timelapse(&frames, output, config.timelapse_fps).map_err(PlantCamError::Encode);
+    }
+
+    if let Some(file) = &args.decrypt {
+        if config.encryption_key.is_empty() {
+            return Err(PlantCamError::Config(anyhow!("--decrypt needs encryption_key to be set")));
+        }
+        let key = crypto::parse_key(&config.encryption_key).map_err(PlantCamError::Config)?;
+        return crypto::decrypt_file(&key, file).map(|_| ()).map_err(PlantCamError::Encode);
+    }
+
+    if args.test_upload {
+        return test_uploads(&config).await;
+    }
+
+    // Gives the network and camera time to come up when started at boot.
+    if config.startup_delay_secs > 0 && !args.make_collage {
+        info!("Waiting {}s before starting, as set by startup_delay_secs.", config.startup_delay_secs);
+        tokio::time::sleep(Duration::from_secs(config.startup_delay_secs)).await;
+    }
+
+    // Built once up front, so bad credentials or backend settings fail at startup.
+    let uploads_needed = config.upload_enabled && !args.dry_run && !args.preview_crop;
+    let destinations = if uploads_needed {
+        Arc::new(get_destinations(&config.destination_configs(), &config).map_err(PlantCamError::Config)?)
+    } else {
+        Arc::new(Vec::new())
+    };
+    let mut camera_destinations = Vec::new();
+    for camera_config in config.camera_configs() {
+        if !uploads_needed || camera_config.destinations.is_empty() {
+            camera_destinations.push(destinations.clone());
+            continue;
+        }
+        let own_destinations = get_destinations(&camera_config.destinations, &config)
+            .with_context(|| format!("Invalid destinations for camera {:?}", camera_config.camera_id))
+            .map_err(PlantCamError::Config)?;
+        camera_destinations.push(Arc::new(own_destinations));
+    }
+
+    if args.make_collage {
+        let today = plant_today(&config);
+        for (camera_config, destinations) in config.camera_configs().iter().zip(&camera_destinations) {
+            for region in camera_config.regions() {
+                upload_collage(&config, &region, destinations, today).await?;
+            }
+        }
+        return Ok(());
+    }
+
+    // Loaded once up front, so a missing or broken watermark fails at startup.
+    let watermark = if config.watermark_path.is_empty() {
+        None
+    } else {
+        Some(Arc::new(load_watermark(Path::new(&config.watermark_path)).map_err(PlantCamError::Config)?))
+    };
+
+    // Only listened for once there is something to interrupt, so a single shot keeps the default Ctrl-C.
+    let mut shutdown = None;
+    let cameras = if config.camera_configs().iter().any(|camera_config| camera_config.is_usb()) {
+        match wait_for_cameras(&config, &mut shutdown).await.map_err(PlantCamError::CameraInit)? {
+            Some(cameras) => cameras,
+            None => return Ok(()),
+        }
+    } else {
+        Vec::new()
+    };
+
+    let mut sessions = Vec::new();
+    for (camera_config, destinations) in config.camera_configs().into_iter().zip(camera_destinations) {
+        match CameraHandle::open(config.clone(), camera_config.clone(), cameras.clone()).await {
+            Ok(camera) => sessions.push(CameraSession {
+                camera_config,
+                destinations,
+                watermark: watermark.clone(),
+                camera: Some(camera),
+                last_uploaded: HashMap::new(),
+                last_frame_hash: None,
+                identical_frames: 0,
+                last_upload_at: Arc::new(Mutex::new(None)),
+                obstructed: HashSet::new(),
+            }),
+            Err(e) => error!("Could not open camera {:?}, skipping it: {:#}.", camera_config.camera_id, e),
+        }
+    }
+    if sessions.is_empty() {
+        return Err(PlantCamError::CameraInit(anyhow!("No cameras could be opened")));
+    }
+
+    if args.preview_crop {
+        for session in sessions.iter_mut() {
+            save_crop_preview(&config, session).await.map_err(PlantCamError::Capture)?;
+        }
+        return Ok(());
+    }
+
+    let mut uploads = Uploads::new(config.max_concurrent_uploads);
+    if config.capture_interval_secs == 0 {
+        let errors = run_all(&config, &mut sessions, &mut uploads, args.dry_run, 0).await;
+        let upload_failures = uploads.finish().await;
+        if errors.is_empty() && upload_failures == 0 {
+            return Ok(());
+        }
+        // Cameras and stored pictures are counted apart, as one camera can yield a picture per crop region.
+        let mut counts = Vec::new();
+        if !errors.is_empty() {
+            counts.push(format!("{} of {} cameras failed", errors.len(), sessions.len()));
+        }
+        if upload_failures > 0 {
+            counts.push(format!("{} of {} pictures failed to store", upload_failures, uploads.spawned));
+        }
+        let summary = counts.join(", ");
+        // Capture failures take precedence, as upload failures can only follow successful captures.
+        return Err(match errors.into_iter().next() {
+            Some(e) => e.context(summary),
+            None => PlantCamError::Upload(anyhow!(summary)),
+        });
+    }
+
+    info!("Capturing every {} seconds.", config.capture_interval_secs);
+    if config.metrics_port != 0 {
+        metrics::spawn_server(config.metrics_port);
+    }
+    if config.serve_port != 0 {
+        let extension = get_output_format(&config).map_err(PlantCamError::Config)?.extension();
+        latest_server::spawn_server(config.serve_port, config.capture_interval_secs, extension);
+    }
+    let schedule = get_schedule(&config).map_err(PlantCamError::Config)?;
+    let timezone = get_timezone(&config).map_err(PlantCamError::Config)?;
+    let mut shutdown = shutdown.unwrap_or_else(listen_for_shutdown);
+    let started = Instant::now();
+    let mut last_usage_report = Instant::now();
+    let mut collage_day = plant_today(&config);
+    loop {
+        if let Some((start, end)) = schedule {
+            let wait = match timezone {
+                Some(timezone) => schedule_wait(start, end, Local::now().with_timezone(&timezone)),
+                None => schedule_wait(start, end, Local::now()),
+            };
+            if let Some((opens_at, until_open)) = wait {
+                info!("Outside the capture schedule, sleeping until {}.", opens_at);
+                tokio::select! {
+                    _ = tokio::time::sleep(until_open) => continue,
+                    _ = shutdown.changed() => break,
+                }
+            }
+        }
+        let interval_secs = get_interval_secs(&config, schedule.is_some());
+        run_all(&config, &mut sessions, &mut uploads, args.dry_run, interval_secs).await;
+        if *shutdown.borrow() {
+            break;
+        }
+        if config.usage_report_interval_secs > 0 && last_usage_report.elapsed() >= Duration::from_secs(config.usage_report_interval_secs) {
+            log_usage_estimate(started.elapsed());
+            last_usage_report = Instant::now();
+        }
+        // The first cycle of a new day sums up the one before.
+        if config.daily_collage && plant_today(&config) != collage_day {
+            for session in &sessions {
+                for region in session.camera_config.regions() {
+                    if let Err(e) = upload_collage(&config, &region, &session.destinations, collage_day).await {
+                        error!("Collage of {} failed: {:#}.", collage_day, e);
+                    }
+                }
+            }
+            collage_day = plant_today(&config);
+        }
+        let mut delay = Duration::from_secs(interval_secs);
+        if config.capture_jitter_secs > 0 {
+            // Spreads out cameras sharing an interval so they don't all upload at once.
+            delay += Duration::from_millis(fastrand::u64(0..=config.capture_jitter_secs * 1000));
+            debug!("Sleeping {:?} until the next capture.", delay);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = shutdown.changed() => break,
+        }
+    }
+    uploads.finish().await;
+    info!("Final capture cycle complete, shutting down.");
+    Ok(())
+}
+
+/// Opens the camera at `camera_device_path`, or else the first of the comma-separated
+/// `camera_id` candidates that is present and opens, falling back to index 0 if none is present.
+async fn open_camera(config: &Config, camera_config: &CameraConfig, cameras: &[CameraInfo]) -> Result<Camera> {
+    if !camera_config.camera_device_path.is_empty() {
+        let camera_index = get_camera_index_by_path(&camera_config.camera_device_path, cameras)?;
+        return start_camera(config, camera_index).await;
+    }
+
+    let candidates = camera_ids(&camera_config.camera_id);
+    let mut last_error = None;
+    for (position, camera_id) in candidates.iter().enumerate() {
+        let Some(camera) = find_camera(camera_id, cameras) else {
+            if candidates.len() > 1 {
+                warn!("Could not find camera with id {}, trying the next one.", camera_id);
+            }
+            continue;
+        };
+        info!("Using camera {} {}.", camera.index(), camera.human_name());
+        match start_camera(config, camera.index()).await {
+            Ok(opened) => {
+                if position > 0 {
+                    warn!("Using fallback camera {} as the preferred ones are unavailable.", camera_id);
+                }
+                return Ok(opened);
+            }
+            Err(e) if position + 1 < candidates.len() => {
+                warn!("Could not open camera {}: {:#}, trying the next one.", camera_id, e);
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    // A camera that was found but failed is more telling than falling back to another one.
+    if let Some(e) = last_error {
+        return Err(e);
+    }
+    if config.no_default_camera {
+        bail!("Could not find camera with id {}", camera_config.camera_id);
+    }
+    warn!("Could not find camera with id {}, using camera with index 0.", camera_config.camera_id);
+    start_camera(config, 0).await
+}
+
+async fn start_camera(config: &Config, camera_index: usize) -> Result<Camera> {
+    let mut camera = get_camera(camera_index, config).await?;
+
+    retry_camera(config, "open stream", || camera.open_stream()).await?;
+    let resolution = camera.resolution();
+    info!(
+        "Camera streaming at {}x{} @ {}fps, requested {}x{} @ {}fps.",
+        resolution.width(), resolution.height(), camera.frame_rate(),
+        config.camera_width, config.camera_height, config.camera_frame_rate,
+    );
+    warm_up_camera(config, &mut camera).await?;
+    Ok(camera)
+}
+
+/// Saves a full frame with the configured crop outlined, to check framing before capturing for real.
+async fn save_crop_preview(config: &Config, session: &mut CameraSession) -> Result<()> {
+    let mut frame = grab_frame(config, &mut session.camera).await?;
+    let outline = Rgb([255, 0, 255]);
+    for region in session.camera_config.regions() {
+        let crop = pixel_crop(config, &region, frame.width(), frame.height());
+        for inset in 0..3 {
+            let width = crop.crop_width.saturating_sub(2 * inset).max(1);
+            let height = crop.crop_height.saturating_sub(2 * inset).max(1);
+            let rect = Rect::at((crop.crop_x + inset) as i32, (crop.crop_y + inset) as i32).of_size(width, height);
+            draw_hollow_rect_mut(&mut frame, rect, outline);
+        }
+        info!(
+            "Crop {:?} is {}x{} at ({}, {}) on a {}x{} frame.",
+            crop.output_prefix, crop.crop_width, crop.crop_height, crop.crop_x, crop.crop_y, frame.width(), frame.height(),
+        );
+    }
+    let output_prefix = &session.camera_config.output_prefix;
+    let path = if output_prefix.is_empty() {
+        PathBuf::from("crop_preview.jpg")
+    } else {
+        PathBuf::from(format!("crop_preview-{}.jpg", output_prefix))
+    };
+    save_file(&path, &encode_image(config, &frame, OutputFormat::Jpeg)?)?;
+    info!("Saved crop preview to {:?}.", path);
+    Ok(())
+}
+
+/// An opened camera together with the state it carries between capture cycles.
+struct CameraSession {
+    camera_config: CameraConfig,
+    /// Where this camera's captures go; empty for dry runs and when uploads are disabled.
+    destinations: Arc<Vec<Destination>>,
+    watermark: Option<Arc<RgbaImage>>,
+    /// `None` after a frame grab timed out, until the camera is reopened.
+    camera: Option<CameraHandle>,
+    /// The image most recently saved for upload from each crop region, by output prefix,
+    /// before overlays, for the motion gate.
+    last_uploaded: HashMap<String, RgbImage>,
+    /// Hash of the last captured frame and how many frames in a row have matched it.
+    last_frame_hash: Option<u64>,
+    identical_frames: u32,
+    /// When a capture from this camera last reached a destination, for `min_upload_interval_secs`;
+    /// set by the background upload.
+    last_upload_at: Arc<Mutex<Option<Instant>>>,
+    /// Output prefixes of the crop regions currently looking obstructed, so the webhook fires once per obstruction.
+    obstructed: HashSet<String>,
+}
+
+/// Captures with each camera in turn. A failing camera is logged and skipped; returns the failures.
+/// Each capture gets a fresh ID, tagging its log lines and uploads. `interval_secs` is the time
+/// until the next cycle, 0 for a single shot.
+async fn run_all(config: &Arc<Config>, sessions: &mut [CameraSession], uploads: &mut Uploads, dry_run: bool, interval_secs: u64) -> Vec<PlantCamError> {
+    let mut failures = Vec::new();
+    for session in sessions.iter_mut() {
+        let capture = run_session(config, session, uploads, dry_run, interval_secs);
+        if let Err(e) = logging::with_capture_id(logging::new_capture_id(), capture).await {
+            failures.push(e);
+        }
+    }
+    failures
+}
+
+async fn run_session(config: &Arc<Config>, session: &mut CameraSession, uploads: &mut Uploads, dry_run: bool, interval_secs: u64) -> Result<(), PlantCamError> {
+    if session.camera.is_none() {
+        if let Err(e) = reconnect_camera(config, session, interval_secs).await {
+            error!("Could not reopen camera {:?}: {:#}.", session.camera_config.camera_id, e);
+            return Err(PlantCamError::CameraInit(e));
+        }
+    }
+    if let Err(e) = run_once(config, session, uploads, dry_run).await {
+        error!("Capture with camera {:?} failed: {:#}.", session.camera_config.camera_id, e);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Reopens a closed camera, doubling the wait after each failed attempt up to
+/// `stream_reconnect_max_backoff_ms`. Gives up once the next capture is due in `interval_secs`,
+/// to try again then, but always retries at least once.
+async fn reconnect_camera(config: &Arc<Config>, session: &mut CameraSession, interval_secs: u64) -> Result<()> {
+    let max_backoff = Duration::from_millis(config.stream_reconnect_max_backoff_ms);
+    let deadline = Instant::now() + Duration::from_secs(interval_secs);
+    let mut backoff = Duration::from_millis(config.camera_init_retry_delay_ms.max(100)).min(max_backoff);
+    let mut attempt = 1;
+    loop {
+        match reopen_camera(config, session).await {
+            Ok(()) => {
+                if attempt > 1 {
+                    info!("Camera {:?} recovered after {} reconnect attempts.", session.camera_config.camera_id, attempt);
+                }
+                return Ok(());
+            }
+            Err(e) if !max_backoff.is_zero() && (attempt == 1 || Instant::now() + backoff < deadline) => {
+                warn!(
+                    "Reconnect attempt {} for camera {:?} failed: {:#}, retrying in {:?}.",
+                    attempt, session.camera_config.camera_id, e, backoff,
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(max_backoff);
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+async fn reopen_camera(config: &Arc<Config>, session: &mut CameraSession) -> Result<()> {
+    info!("Reopening camera {:?}.", session.camera_config.camera_id);
+    let cameras = if session.camera_config.is_usb() { get_cameras()? } else { Vec::new() };
+    session.camera = Some(CameraHandle::open(config.clone(), session.camera_config.clone(), cameras).await?);
+    Ok(())
+}
+
+/// Tiles the captures one crop region saved on `day` into one picture, saved under `collages/`
+/// in `output_dir` and uploaded to the `collages/` folder.
+async fn upload_collage(config: &Config, region: &CameraConfig, destinations: &[Destination], day: NaiveDate) -> Result<(), PlantCamError> {
+    let format = get_output_format(config).map_err(PlantCamError::Config)?;
+    let frames = captures_on(config, &region.output_prefix, format, day);
+    let Some(last_frame) = frames.last() else {
+        info!("No captures from {} to make a collage of.", day);
+        return Ok(());
+    };
+    // Dates the collage for key_layout "date" by its last frame, which fell on `day`.
+    let last_captured_at = fs::metadata(last_frame)
+        .and_then(|metadata| metadata.modified())
+        .map(DateTime::<Local>::from)
+        .map_err(|e| PlantCamError::Encode(anyhow!("Could not read {:?}: {}", last_frame, e)))?;
+    let collage = collage::make_collage(&frames, config.collage_tile_width).map_err(PlantCamError::Encode)?;
+    let buffer = encode_image(config, &collage, format).map_err(PlantCamError::Encode)?;
+
+    let mut filename = format!("{}.{}", day.format("%Y%m%d"), format.extension());
+    if !region.output_prefix.is_empty() {
+        filename = format!("{}-{}", region.output_prefix, filename);
+    }
+    let path = Path::new(&config.output_dir).join("collages").join(&filename);
+    save_file(&path, &buffer).map_err(PlantCamError::Upload)?;
+    info!("Saved collage to {:?}.", path);
+    let key = get_object_key(config, "collages", &path, last_captured_at);
+    let encryption_key = get_encryption_key(config)?;
+    let (key, upload_buffer, content_type) = seal(encryption_key.as_ref(), key, &buffer, format.content_type())?;
+    let mut failed = Vec::new();
+    for destination in destinations {
+        if !upload_with_retry(config, destination, &key, &upload_buffer, content_type).await {
+            failed.push(destination.name.as_str());
+        }
+    }
+    if !failed.is_empty() {
+        return Err(PlantCamError::Upload(anyhow!("Failed to upload collage {} to {}", key, failed.join(", "))));
+    }
+    Ok(())
+}
+
+/// Saved captures last modified on `day` where the plant is, oldest first.
+fn captures_on(config: &Config, output_prefix: &str, format: OutputFormat, day: NaiveDate) -> Vec<PathBuf> {
+    list_captures(&config.output_dir, output_prefix, format)
+        .into_iter()
+        .filter(|path| {
+            fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .map(|modified| plant_time(config, DateTime::<Local>::from(modified)).naive_local().date() == day)
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+fn plant_today(config: &Config) -> NaiveDate {
+    plant_time(config, Local::now()).naive_local().date()
+}
+
+/// Extrapolates the uploads so far to a day and a 30-day month. Objects that are overwritten,
+/// like the latest copy, count towards transfer but overstate the storage growth.
+fn log_usage_estimate(elapsed: Duration) {
+    let (captures, bytes) = (metrics::captures(), metrics::uploaded_bytes());
+    if captures == 0 || elapsed.is_zero() {
+        return;
+    }
+    let per_day = |count: u64| count as f64 * 86400.0 / elapsed.as_secs_f64();
+    info!(
+        "Uploaded {} over {} captures in {:.1}h, {} per capture. At {:.0} captures a day that is about {} a day and {} a month.",
+        format_bytes(bytes as f64),
+        captures,
+        elapsed.as_secs_f64() / 3600.0,
+        format_bytes(bytes as f64 / captures as f64),
+        per_day(captures),
+        format_bytes(per_day(bytes)),
+        format_bytes(per_day(bytes) * 30.0),
+    );
+}
+
+fn format_bytes(bytes: f64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < units.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, units[unit])
+}
+
+/// Seconds between capture cycles, `schedule_interval_secs` when set and a schedule is in use.
+fn get_interval_secs(config: &Config, scheduled: bool) -> u64 {
+    if scheduled && config.schedule_interval_secs > 0 {
+        config.schedule_interval_secs
+    } else {
+        config.capture_interval_secs
+    }
+}
+
+/// The configured capture window as start and end times, or `None` to capture around the clock.
+fn get_schedule(config: &Config) -> Result<Option<(NaiveTime, NaiveTime)>> {
+    if config.schedule_start.is_empty() && config.schedule_end.is_empty() {
+        return Ok(None);
+    }
+    let parse = |field: &str, value: &str| {
+        NaiveTime::parse_from_str(value, "%H:%M").with_context(|| format!("{} must be a time such as \"07:00\", got {:?}", field, value))
+    };
+    let (start, end) = (parse("schedule_start", &config.schedule_start)?, parse("schedule_end", &config.schedule_end)?);
+    if start == end {
+        bail!("schedule_start and schedule_end are both {:?}, leaving an empty window; leave both empty to capture around the clock", config.schedule_start);
+    }
+    Ok(Some((start, end)))
+}
+
+/// Whether `time` falls in the window, which may wrap past midnight such as 22:00 to 06:00.
+fn in_schedule(start: NaiveTime, end: NaiveTime, time: NaiveTime) -> bool {
+    if start <= end {
+        time >= start && time < end
+    } else {
+        time >= start || time < end
+    }
+}
+
+/// The next time the window opens after `now`, today or tomorrow.
+fn next_schedule_start<Tz: TimeZone>(start: NaiveTime, now: DateTime<Tz>) -> DateTime<Tz> {
+    let timezone = now.timezone();
+    let mut opens_at = now.naive_local().date().and_time(start);
+    if opens_at <= now.naive_local() {
+        opens_at += chrono::Duration::days(1);
+    }
+    // A start time skipped by a DST change falls back to an hour later.
+    timezone
+        .from_local_datetime(&opens_at)
+        .earliest()
+        .unwrap_or_else(|| timezone.from_local_datetime(&(opens_at + chrono::Duration::hours(1))).earliest().unwrap_or(now))
+}
+
+/// When `now` is outside the window, when it opens and how long until then.
+fn schedule_wait<Tz: TimeZone>(start: NaiveTime, end: NaiveTime, now: DateTime<Tz>) -> Option<(String, Duration)>
+where
+    Tz::Offset: std::fmt::Display,
+{
+    if in_schedule(start, end, now.time()) {
+        return None;
+    }
+    let opens_at = next_schedule_start(start, now.clone());
+    let until_open = (opens_at.clone() - now).to_std().unwrap_or_default();
+    Some((opens_at.format("%Y-%m-%d %H:%M").to_string(), until_open))
+}
+
+/// The configured `timezone`, or `None` for the system's local time.
+fn get_timezone(config: &Config) -> Result<Option<Tz>> {
+    if config.timezone.is_empty() {
+        return Ok(None);
+    }
+    let timezone = config
+        .timezone
+        .parse()
+        .map_err(|_| anyhow!("timezone must be an IANA name such as \"Europe/London\", got {:?}", config.timezone))?;
+    Ok(Some(timezone))
+}
+
+/// `time` as the wall clock shows it where the plant is, for filenames, captions and EXIF.
+fn plant_time(config: &Config, time: DateTime<Local>) -> DateTime<FixedOffset> {
+    let offset = match get_timezone(config) {
+        Ok(Some(timezone)) => time.with_timezone(&timezone).offset().fix(),
+        _ => time.offset().fix(),
+    };
+    time.with_timezone(&offset)
+}
+
+/// Checks every destination, including per-camera ones, with a marker object.
+async fn test_uploads(config: &Config) -> Result<(), PlantCamError> {
+    let mut destinations = get_destinations(&config.destination_configs(), config).map_err(PlantCamError::Config)?;
+    for camera_config in config.camera_configs() {
+        destinations.extend(get_destinations(&camera_config.destinations, config).map_err(PlantCamError::Config)?);
+    }
+    let key = format!("{}plant-cam-test-{}.txt", config.r2_project_prefix, Local::now().format("%Y%m%d_%H%M%S"));
+    let mut failed = Vec::new();
+    for destination in &destinations {
+        match upload::test_destination(destination, &key).await {
+            Ok(()) => info!("Test upload to {} succeeded.", destination.name),
+            Err(e) => {
+                error!("Test upload to {} failed: {:#}.", destination.name, e);
+                failed.push(destination.name.as_str());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        return Err(PlantCamError::Upload(anyhow!("Test upload failed for {}", failed.join(", "))));
+    }
+    info!("All {} destination(s) work.", destinations.len());
+    Ok(())
+}
+
+/// Flips to `true` once SIGINT or SIGTERM is received, so the loop can finish its current cycle.
+fn listen_for_shutdown() -> watch::Receiver<bool> {
+    let (sender, receiver) = watch::channel(false);
+    tokio::spawn(async move {
+        let signal_name = wait_for_shutdown_signal().await;
+        info!("Received {}, finishing current cycle before shutting down.", signal_name);
+        sender.send(true).ok();
+    });
+    receiver
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = terminate.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() -> &'static str {
+    tokio::signal::ctrl_c().await.expect("Failed to install Ctrl-C handler");
+    "Ctrl-C"
+}
+
+async fn run_once(config: &Arc<Config>, session: &mut CameraSession, uploads: &mut Uploads, dry_run: bool) -> Result<(), PlantCamError> {
+    let output_format = get_output_format(config).map_err(PlantCamError::Config)?;
+    if !config.pre_capture_command.is_empty() {
+        hooks::run_hook("pre-capture", &config.pre_capture_command, config.hook_timeout_secs, &[]).await;
+    }
+    let sensor_text = if config.sensor_data_path.is_empty() {
+        None
+    } else {
+        match read_sensor_data(&config.sensor_data_path) {
+            Ok(text) => Some(text),
+            Err(e) => {
+                warn!("Skipping the sensor overlay: {:#}.", e);
+                None
+            }
+        }
+    };
+    let captured_at = Local::now();
+
+    // With several crop regions, or when the full frame is uploaded too, the whole frame is kept
+    // and cropped once per region below.
+    let regions = session.camera_config.regions();
+    let crop = if session.camera_config.crop_regions.is_empty() && !config.upload_full_frame { Some(&regions[0]) } else { None };
+    // A GIF keeps the whole burst as an animation rather than picking the sharpest frame.
+    let (frame, mut animation) = if config.burst_count > 1 && output_format == OutputFormat::Gif {
+        let mut frames = capture_burst(config, crop, &mut session.camera).await.map_err(PlantCamError::Capture)?;
+        (frames.remove(0), frames)
+    } else if config.burst_count > 1 {
+        (capture_sharpest(config, crop, &regions[0], &mut session.camera).await.map_err(PlantCamError::Capture)?, Vec::new())
+    } else {
+        (capture_cropped(config, crop, &mut session.camera).await.map_err(PlantCamError::Capture)?, Vec::new())
+    };
+    metrics::record_capture();
+
+    // A single shot has no previous frame to compare against.
+    if config.frozen_frame_threshold > 0 && config.capture_interval_secs > 0 {
+        let mut hasher = DefaultHasher::new();
+        frame.as_raw().hash(&mut hasher);
+        let hash = hasher.finish();
+        session.identical_frames = if session.last_frame_hash == Some(hash) { session.identical_frames + 1 } else { 1 };
+        session.last_frame_hash = Some(hash);
+        if session.identical_frames >= config.frozen_frame_threshold {
+            session.identical_frames = 0;
+            session.last_frame_hash = None;
+            if config.reopen_frozen_camera {
+                session.camera = None;
+            }
+            return Err(PlantCamError::Capture(anyhow!(
+                "{} identical frames in a row, the camera looks frozen", config.frozen_frame_threshold,
+            )));
+        }
+    }
+    let last_upload_at = *session.last_upload_at.lock().unwrap();
+    if let Some(last_upload_at) = last_upload_at {
+        let since_upload = last_upload_at.elapsed();
+        if since_upload < Duration::from_secs(config.min_upload_interval_secs) {
+            info!(
+                "Last upload was {}s ago, under min_upload_interval_secs {}, skipping this frame.",
+                since_upload.as_secs(), config.min_upload_interval_secs,
+            );
+            return Ok(());
+        }
+    }
+
+    let mut captures = Vec::new();
+    for region in &regions {
+        let frames: Vec<RgbImage> = match crop {
+            Some(_) => std::iter::once(frame.clone()).chain(animation.drain(..)).collect(),
+            None => std::iter::once(&frame)
+                .chain(animation.iter())
+                .map(|frame| crop_frame(config, region, frame))
+                .collect::<Result<_>>()
+                .map_err(PlantCamError::Capture)?,
+        };
+        if config.obstruction_detection {
+            check_obstruction(config, session, region, &frames[0], captured_at);
+        }
+        if let Some(capture) = process_image(config, session, region, frames, output_format, captured_at, sensor_text.as_deref())? {
+            captures.push(capture);
+        }
+    }
+    if config.upload_full_frame && !captures.is_empty() {
+        let full_frame = encode_image(config, &frame, output_format).map_err(PlantCamError::Encode)?;
+        captures[0].full_frame = Some((get_full_frame_path(&captures[0].output_path), full_frame));
+    }
+    for capture in captures {
+        uploads.spawn(config, session.destinations.clone(), session.last_upload_at.clone(), capture, dry_run).await;
+    }
+    Ok(())
+}
+
+/// Warns when the cropped `image` is nearly uniform, e.g. a leaf against the lens, and with
+/// `obstruction_webhook` notifies the webhook when an obstruction starts.
+fn check_obstruction(config: &Arc<Config>, session: &mut CameraSession, region: &CameraConfig, image: &RgbImage, captured_at: DateTime<Local>) {
+    let stddev = luminance_stddev(image);
+    if stddev >= config.obstruction_stddev_threshold {
+        if session.obstructed.remove(&region.output_prefix) {
+            info!("Crop {:?} no longer looks obstructed.", region.output_prefix);
+        }
+        return;
+    }
+    let message = format!(
+        "Crop {:?} looks obstructed: luminance standard deviation {:.1} is below obstruction_stddev_threshold {:.1}",
+        region.output_prefix, stddev, config.obstruction_stddev_threshold,
+    );
+    warn!("{}.", message);
+    let started = session.obstructed.insert(region.output_prefix.clone());
+    if started && config.obstruction_webhook && !config.webhook_url.is_empty() {
+        let config = config.clone();
+        let payload = WebhookPayload {
+            object_key: None,
+            timestamp: plant_time(&config, captured_at).to_rfc3339(),
+            public_url: None,
+            alert: Some(message),
+        };
+        tokio::spawn(async move { send_webhook(&config, &payload).await });
+    }
+}
+
+/// Runs one cropped image through the gates, corrections and overlays, and encodes it.
+/// Any further `frames` after the first get the same corrections and overlays and follow it in a GIF.
+/// Returns `None` when a gate skips it.
+fn process_image(
+    config: &Config,
+    session: &mut CameraSession,
+    camera_config: &CameraConfig,
+    mut frames: Vec<RgbImage>,
+    output_format: OutputFormat,
+    captured_at: DateTime<Local>,
+    sensor_text: Option<&str>,
+) -> Result<Option<Capture>, PlantCamError> {
+    let camera = session.camera.as_ref().expect("Camera is present after a successful capture");
+    let mut image = frames.remove(0);
+    let animation = frames;
+
+    if config.log_histogram {
+        color_correction::log_histogram(&image);
+    }
+
+    if config.min_mean_brightness > 0.0 {
+        let brightness = mean_brightness(&image);
+        if brightness < config.min_mean_brightness {
+            info!("Mean brightness {:.1} is below {:.1}, skipping this frame.", brightness, config.min_mean_brightness);
+            return Ok(None);
+        }
+        info!("Mean brightness is {:.1}.", brightness);
+    }
+
+    if config.target_brightness_min > 0.0 || config.target_brightness_max > 0.0 {
+        let brightness = mean_brightness(&image);
+        let hint = if config.target_brightness_min > 0.0 && brightness < config.target_brightness_min {
+            Some(format!("below the target minimum {:.1}, consider raising the exposure or adding light", config.target_brightness_min))
+        } else if config.target_brightness_max > 0.0 && brightness > config.target_brightness_max {
+            Some(format!("above the target maximum {:.1}, consider lowering the exposure or the light", config.target_brightness_max))
+        } else {
+            None
+        };
+        if let Some(hint) = hint {
+            warn!("Mean brightness {:.1} is {}.", brightness, hint);
+            if config.skip_outside_brightness_target {
+                info!("Skipping this frame as it is outside the target brightness.");
+                return Ok(None);
+            }
+        }
+    }
+
+    image = correct_image(config, image)?;
+
+    if config.motion_threshold > 0.0 {
+        if let Some(previous) = session.last_uploaded.get(&camera_config.output_prefix) {
+            let difference = mean_absolute_difference(previous, &image);
+            info!("Mean difference from the last uploaded frame is {:.2}.", difference);
+            if difference < config.motion_threshold {
+                info!("Below motion threshold {:.2}, skipping this frame.", config.motion_threshold);
+                return Ok(None);
+            }
+        }
+        session.last_uploaded.insert(camera_config.output_prefix.clone(), image.clone());
+    }
+
+    annotate_image(config, session.watermark.as_deref(), &mut image, captured_at, sensor_text);
+
+    let mut image_buffer = if animation.is_empty() {
+        encode_image(config, &image, output_format).map_err(PlantCamError::Encode)?
+    } else {
+        let mut frames = vec![image.clone()];
+        for frame in animation {
+            let mut frame = correct_image(config, frame)?;
+            annotate_image(config, session.watermark.as_deref(), &mut frame, captured_at, sensor_text);
+            frames.push(frame);
+        }
+        encode_gif(config, &frames).map_err(PlantCamError::Encode)?
+    };
+    let output_path = get_output_path(config, &camera_config.output_prefix, output_format, captured_at);
+    // Hashed before EXIF is added, as its capture time would make every picture unique.
+    let key = if config.hash_in_key {
+        get_object_key(config, "pictures", &get_hashed_filename(&camera_config.output_prefix, output_format, &image_buffer), captured_at)
+    } else {
+        get_object_key(config, "pictures", &output_path, captured_at)
+    };
+    if config.embed_exif && output_format == OutputFormat::Jpeg {
+        image_buffer = embed_exif(&image_buffer, camera.info(), plant_time(config, captured_at)).map_err(PlantCamError::Encode)?;
+    }
+    if config.serve_port != 0 {
+        latest_server::set_latest(image_buffer.clone(), output_format.content_type(), config.recent_buffer_size);
+    }
+
+    let thumbnail = if config.thumbnail_width > 0 {
+        let thumbnail = make_thumbnail(&image, config.thumbnail_width);
+        Some((get_thumbnail_path(&output_path), encode_image(config, &thumbnail, output_format).map_err(PlantCamError::Encode)?))
+    } else {
+        None
+    };
+
+    let sidecar = config.write_sidecar.then(|| {
+        let crop = pixel_crop(config, camera_config, camera.resolution().width(), camera.resolution().height());
+        let record = CaptureRecord {
+            timestamp: plant_time(config, captured_at).to_rfc3339(),
+            camera_index: camera.index(),
+            camera_name: camera.info().human_name(),
+            width: camera.resolution().width(),
+            height: camera.resolution().height(),
+            frame_rate: camera.frame_rate(),
+            requested_width: config.camera_width,
+            requested_height: config.camera_height,
+            requested_frame_rate: config.camera_frame_rate,
+            crop_x: crop.crop_x,
+            crop_y: crop.crop_y,
+            crop_width: crop.crop_width,
+            crop_height: crop.crop_height,
+            output_format: output_format.extension().to_string(),
+            object_key: sealed_key(config, &key),
+            capture_id: logging::capture_id().unwrap_or_default(),
+        };
+        (output_path.with_extension("json"), serde_json::to_vec_pretty(&record).unwrap())
+    });
+
+    let capture = Capture {
+        output_prefix: camera_config.output_prefix.clone(),
+        output_format,
+        captured_at,
+        output_path,
+        key,
+        image_buffer,
+        thumbnail,
+        full_frame: None,
+        sidecar,
+    };
+    Ok(Some(capture))
+}
+
+/// Runs the operations listed in `pipeline`, or by default every enabled one in the usual order.
+fn correct_image(config: &Config, mut image: RgbImage) -> Result<RgbImage, PlantCamError> {
+    for op in image_pipeline(config).map_err(PlantCamError::Config)? {
+        image = op(config, image);
+    }
+    Ok(image)
+}
+
+/// An image operation that can be named in `pipeline`, configured by its own settings.
+type ImageOp = fn(&Config, RgbImage) -> RgbImage;
+
+/// Every operation `pipeline` can name, in the order they run when it is empty. Flips come
+/// before any rotation, so they act on the picture as the sensor sees it.
+const IMAGE_OPS: [(&str, ImageOp); 9] = [
+    ("white_balance", |_, image| color_correction::gray_world(image)),
+    ("gamma", |config, image| color_correction::gamma(image, config.gamma)),
+    ("flip_horizontal", |_, image| flip_horizontal(&image)),
+    ("flip_vertical", |_, image| flip_vertical(&image)),
+    ("rotate_fine", |config, image| {
+        info!("Rotating by {:.2} degrees to level the picture.", config.rotate_fine_degrees);
+        rotate_about_center(&image, config.rotate_fine_degrees.to_radians(), Interpolation::Bilinear, Rgb(config.rotate_fill_color))
+    }),
+    ("rotate", |config, image| rotate(image, config.rotate_degrees)),
+    ("limit_size", |config, image| limit_size(image, config.max_output_width, config.max_output_height)),
+    ("sharpen", |config, image| sharpen(image, config.sharpen_amount, config.sharpen_sigma, config.sharpen_threshold)),
+    ("letterbox", |config, image| match parse_aspect_ratio(&config.output_aspect_ratio) {
+        Ok(Some(aspect_ratio)) => letterbox(image, aspect_ratio, Rgb(config.letterbox_color)),
+        _ => image,
+    }),
+];
+
+fn find_image_op(name: &str) -> Option<ImageOp> {
+    IMAGE_OPS.iter().find(|(op_name, _)| op_name.eq_ignore_ascii_case(name)).map(|(_, op)| *op)
+}
+
+/// Whether an operation runs when `pipeline` is empty, going by the settings that enabled it
+/// before the pipeline existed.
+fn image_op_enabled(config: &Config, name: &str) -> bool {
+    match name {
+        "white_balance" => config.auto_white_balance,
+        "gamma" => config.gamma != 1.0,
+        "flip_horizontal" => config.flip_horizontal,
+        "flip_vertical" => config.flip_vertical,
+        "rotate_fine" => config.rotate_fine_degrees != 0.0,
+        "rotate" => config.rotate_degrees != 0,
+        "limit_size" => config.max_output_width > 0 || config.max_output_height > 0,
+        "sharpen" => config.sharpen_amount > 0.0,
+        "letterbox" => !config.output_aspect_ratio.is_empty(),
+        _ => false,
+    }
+}
+
+fn image_pipeline(config: &Config) -> Result<Vec<ImageOp>> {
+    if config.pipeline.is_empty() {
+        return Ok(IMAGE_OPS.iter().filter(|(name, _)| image_op_enabled(config, name)).map(|(_, op)| *op).collect());
+    }
+    config
+        .pipeline
+        .iter()
+        .map(|name| find_image_op(name).ok_or_else(|| anyhow!("Unknown pipeline operation {:?}", name)))
+        .collect()
+}
+
+/// Draws the timestamp, sensor readings and watermark onto `image`.
+fn annotate_image(config: &Config, watermark: Option<&RgbaImage>, image: &mut RgbImage, captured_at: DateTime<Local>, sensor_text: Option<&str>) {
+    if config.overlay_timestamp {
+        let timestamp = plant_time(config, captured_at).format("%Y-%m-%d %H:%M:%S").to_string();
+        draw_text_overlay(image, &timestamp, config.overlay_font_size, config.overlay_position);
+    }
+    if let Some(sensor_text) = sensor_text {
+        draw_text_overlay(image, sensor_text, config.overlay_font_size, config.sensor_overlay_position);
+    }
+    if let Some(watermark) = watermark {
+        draw_watermark(image, watermark, config.watermark_opacity, config.watermark_position);
+    }
+}
+
+/// Everything needed to save and upload a capture once it has left the camera.
+struct Capture {
+    output_prefix: String,
+    output_format: OutputFormat,
+    captured_at: DateTime<Local>,
+    output_path: PathBuf,
+    key: String,
+    image_buffer: Vec<u8>,
+    thumbnail: Option<(PathBuf, Vec<u8>)>,
+    /// The uncropped frame, attached to only one of the captures taken from it.
+    full_frame: Option<(PathBuf, Vec<u8>)>,
+    sidecar: Option<(PathBuf, Vec<u8>)>,
+}
+
+/// Saves and uploads captures in the background, so a slow upload doesn't delay the next capture.
+/// At most `max_concurrent_uploads` run at once; further captures wait for a free slot.
+struct Uploads {
+    permits: Arc<Semaphore>,
+    tasks: Vec<JoinHandle<()>>,
+    /// Captures handed off so far, and how many of them failed.
+    spawned: usize,
+    failures: Arc<AtomicUsize>,
+}
+
+impl Uploads {
+    fn new(max_concurrent_uploads: usize) -> Self {
+        Uploads {
+            permits: Arc::new(Semaphore::new(max_concurrent_uploads.max(1))),
+            tasks: Vec::new(),
+            spawned: 0,
+            failures: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    async fn spawn(
+        &mut self,
+        config: &Arc<Config>,
+        destinations: Arc<Vec<Destination>>,
+        last_upload_at: Arc<Mutex<Option<Instant>>>,
+        capture: Capture,
+        dry_run: bool,
+    ) {
+        let permit = match self.permits.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!("Uploads are not keeping up with the capture rate, waiting for one to finish.");
+                self.permits.clone().acquire_owned().await.expect("Upload semaphore closed")
+            }
+        };
+        let config = config.clone();
+        let failures = self.failures.clone();
+        self.spawned += 1;
+        let (finished, running) = std::mem::take(&mut self.tasks).into_iter().partition::<Vec<_>, _>(|task| task.is_finished());
+        self.tasks = running;
+        for task in finished {
+            self.record_join(task.await);
+        }
+        let task = async move {
+            let output_path = capture.output_path.clone();
+            if let Err(e) = store_capture(&config, &destinations, &last_upload_at, capture, dry_run).await {
+                error!("Storing {:?} failed: {:#}.", output_path, e);
+                failures.fetch_add(1, Ordering::Relaxed);
+            }
+            drop(permit);
+        };
+        // Carried over so the upload's log lines and metadata share the capture's ID.
+        self.tasks.push(match logging::capture_id() {
+            Some(capture_id) => tokio::spawn(logging::with_capture_id(capture_id, task)),
+            None => tokio::spawn(task),
+        });
+    }
+
+    /// Waits for every upload in flight and returns how many have failed so far.
+    async fn finish(&mut self) -> usize {
+        if !self.tasks.is_empty() {
+            debug!("Waiting for {} upload(s) to finish.", self.tasks.len());
+        }
+        for task in std::mem::take(&mut self.tasks) {
+            self.record_join(task.await);
+        }
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Counts an upload task that panicked or was cancelled as a failed upload.
+    fn record_join(&self, result: Result<(), JoinError>) {
+        if let Err(e) = result {
+            error!("Upload task failed: {}.", e);
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Saves a capture locally and uploads it, recording a successful upload in `last_upload_at`.
+async fn store_capture(
+    config: &Config,
+    destinations: &[Destination],
+    last_upload_at: &Mutex<Option<Instant>>,
+    capture: Capture,
+    dry_run: bool,
+) -> Result<(), PlantCamError> {
+    let Capture { output_prefix, output_format, captured_at, output_path, key, image_buffer, thumbnail, full_frame, sidecar } = capture;
+    let save_locally = !config.skip_local_save
+        && (config.min_free_disk_mb == 0 || ensure_free_disk_space(config, &output_prefix, output_format));
+    if save_locally {
+        save_file(&output_path, &image_buffer).map_err(PlantCamError::Upload)?;
+        for (path, content) in thumbnail.iter().chain(full_frame.iter()).chain(sidecar.iter()) {
+            save_file(path, content).map_err(PlantCamError::Upload)?;
+        }
+    }
+
+    if dry_run {
+        info!("Dry run, skipping upload of {:?}.", output_path);
+        return Ok(());
+    }
+    if destinations.is_empty() {
+        info!("Uploads are disabled, keeping {:?} locally only.", output_path);
+        if config.local_retention_count > 0 {
+            apply_local_retention(config, &output_prefix, output_format);
+        }
+        return Ok(());
+    }
+
+    info!("Updating image.");
+    // Local copies stay readable; only what leaves the machine is encrypted, once for all destinations.
+    let encryption_key = get_encryption_key(config)?;
+    let encryption_key = encryption_key.as_ref();
+    let content_type = output_format.content_type();
+    let (key, upload_buffer, upload_content_type) = seal(encryption_key, key, &image_buffer, content_type)?;
+    let latest = if config.maintain_latest {
+        let latest_key = format!("{}pictures/{}.{}", config.r2_project_prefix, config.latest_name, output_format.extension());
+        Some(seal(encryption_key, latest_key, &image_buffer, content_type)?)
+    } else {
+        None
+    };
+    let thumbnail = match &thumbnail {
+        Some((path, buffer)) => Some(seal(encryption_key, get_object_key(config, "thumbnails", path, captured_at), buffer, content_type)?),
+        None => None,
+    };
+    let full_frame = match &full_frame {
+        Some((path, buffer)) => Some(seal(encryption_key, get_object_key(config, "full", path, captured_at), buffer, content_type)?),
+        None => None,
+    };
+    let sidecar = match &sidecar {
+        Some((path, buffer)) => Some(seal(encryption_key, get_object_key(config, "pictures", path, captured_at), buffer, "application/json")?),
+        None => None,
+    };
+
+    // Every destination gets its copy even if an earlier one failed.
+    let mut failed = Vec::new();
+    for destination in destinations {
+        if config.hash_in_key && config.skip_existing_hash {
+            match destination.uploader.exists(&key).await {
+                Ok(true) => {
+                    info!("{} already holds {}, skipping the upload.", destination.name, key);
+                    continue;
+                }
+                Ok(false) => {}
+                Err(e) => warn!("Could not check for {} on {}, uploading anyway: {:#}.", key, destination.name, e),
+            }
+        }
+        if !upload_with_retry(config, destination, &key, &upload_buffer, upload_content_type).await {
+            warn!("Failed to upload {} to {}.", key, destination.name);
+            metrics::record_upload_failure();
+            failed.push(destination.name.as_str());
+            continue;
+        }
+        metrics::record_upload_success(captured_at.timestamp());
+        if let Some((latest_key, latest_buffer, latest_content_type)) = &latest {
+            if !upload_with_retry(config, destination, latest_key, latest_buffer, latest_content_type).await {
+                warn!("Failed to update {} on {}.", latest_key, destination.name);
+            }
+        }
+        if config.maintain_index {
+            update_index(config, destination, &key, captured_at, encryption_key).await;
+        }
+        if let Some((thumbnail_key, thumbnail_buffer, thumbnail_content_type)) = &thumbnail {
+            if !upload_with_retry(config, destination, thumbnail_key, thumbnail_buffer, thumbnail_content_type).await {
+                warn!("Failed to upload thumbnail {} to {}.", thumbnail_key, destination.name);
+            }
+        }
+        if let Some((full_frame_key, full_frame_buffer, full_frame_content_type)) = &full_frame {
+            if !upload_with_retry(config, destination, full_frame_key, full_frame_buffer, full_frame_content_type).await {
+                warn!("Failed to upload full frame {} to {}.", full_frame_key, destination.name);
+            }
+        }
+        if let Some((sidecar_key, sidecar_buffer, sidecar_content_type)) = &sidecar {
+            if !upload_with_retry(config, destination, sidecar_key, sidecar_buffer, sidecar_content_type).await {
+                warn!("Failed to upload sidecar {} to {}.", sidecar_key, destination.name);
+            }
+        }
+    }
+    // One reachable destination is enough to count the capture as uploaded; the metrics above
+    // count each destination on its own.
+    let uploaded = failed.len() < destinations.len();
+    if uploaded {
+        *last_upload_at.lock().unwrap() = Some(Instant::now());
+        if let Some(public_url) = get_public_url(config, &key) {
+            info!("Picture available at {}.", public_url);
+        }
+        if !config.healthcheck_file.is_empty() {
+            touch_healthcheck(&config.healthcheck_file);
+        }
+    } else {
+        if !config.keep_local_on_upload_failure {
+            return Err(PlantCamError::Upload(anyhow!("Failed to upload {} to {}", key, failed.join(", "))));
+        }
+        if config.skip_local_save {
+            save_file(&output_path, &image_buffer).map_err(PlantCamError::Upload)?;
+        }
+        if save_locally || config.skip_local_save {
+            warn!("Keeping {:?} locally after failed upload.", output_path);
+        } else {
+            error!("Upload failed and there was no disk space to keep {:?} locally.", output_path);
+        }
+    }
+    if uploaded && !config.webhook_url.is_empty() {
+        let payload = WebhookPayload {
+            object_key: Some(key.clone()),
+            timestamp: plant_time(config, captured_at).to_rfc3339(),
+            public_url: get_public_url(config, &key),
+            alert: None,
+        };
+        send_webhook(config, &payload).await;
+    }
+    if uploaded && !config.post_upload_command.is_empty() {
+        let output_path = output_path.to_string_lossy();
+        let env = [("PLANT_CAM_OUTPUT_PATH", output_path.as_ref()), ("PLANT_CAM_OBJECT_KEY", key.as_str())];
+        hooks::run_hook("post-upload", &config.post_upload_command, config.hook_timeout_secs, &env).await;
+    }
+    if uploaded && config.delete_after_upload && save_locally {
+        delete_capture(&output_path, "after upload");
+    }
+
+    if uploaded && config.local_retention_count > 0 {
+        apply_local_retention(config, &output_prefix, output_format);
+    }
+    Ok(())
+}
+
+fn get_encryption_key(config: &Config) -> Result<Option<[u8; 32]>, PlantCamError> {
+    if config.encryption_key.is_empty() {
+        return Ok(None);
+    }
+    crypto::parse_key(&config.encryption_key).map(Some).map_err(PlantCamError::Config)
+}
+
+/// The key `seal` uploads `key` under, for records written before the upload.
+fn sealed_key(config: &Config, key: &str) -> String {
+    if config.encryption_key.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.enc", key)
+    }
+}
+
+/// With `encryption_key` set, encrypts `content` and marks `key` with a `.enc` extension;
+/// otherwise passes both through.
+fn seal<'a>(
+    encryption_key: Option<&[u8; 32]>,
+    key: String,
+    content: &'a [u8],
+    content_type: &'static str,
+) -> Result<(String, Cow<'a, [u8]>, &'static str), PlantCamError> {
+    match encryption_key {
+        Some(encryption_key) => {
+            let encrypted = crypto::encrypt(encryption_key, content).map_err(PlantCamError::Encode)?;
+            Ok((format!("{}.enc", key), Cow::Owned(encrypted), "application/octet-stream"))
+        }
+        None => Ok((key, Cow::Borrowed(content), content_type)),
+    }
+}
+
+/// Machine-readable record of a capture, written next to the image as JSON.
+#[derive(Debug, Serialize)]
+struct CaptureRecord {
+    timestamp: String,
+    camera_index: usize,
+    camera_name: String,
+    /// The negotiated frame size and rate, which the camera may have substituted for the requested ones.
+    width: u32,
+    height: u32,
+    frame_rate: u32,
+    requested_width: u32,
+    requested_height: u32,
+    requested_frame_rate: u32,
+    crop_x: u32,
+    crop_y: u32,
+    crop_width: u32,
+    crop_height: u32,
+    output_format: String,
+    object_key: String,
+    capture_id: String,
+}
+
+/// The `index.json` manifest listing the latest uploads, oldest first.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Index {
+    entries: Vec<IndexEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    key: String,
+    timestamp: String,
+}
+
+#[derive(Debug, Serialize)]
+struct WebhookPayload {
+    /// `None` for alerts, which have no upload to point at.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    object_key: Option<String>,
+    timestamp: String,
+    public_url: Option<String>,
+    /// Set instead of an upload for warnings such as an obstructed lens.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alert: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    Jpeg,
+    Png,
+    WebP,
+    Avif,
+    Gif,
+}
+
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Gif => "gif",
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Gif => "image/gif",
+        }
+    }
+}
+
+const ENV_PREFIX: &str = "PLANTCAM_";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct Config {
+    camera_id: String,
+    camera_width: u32,
+    camera_height: u32,
+    camera_frame_rate: u32,
+    output_dir: String,
+    output_prefix: String,
+    output_format: String,
+    crop_x: u32,
+    crop_y: u32,
+    crop_width: u32,
+    crop_height: u32,
+    no_default_camera: bool,
+    r2_accound_id: String,
+    r2_bucket_name: String,
+    r2_access_key_id: String,
+    r2_secret_access_key: String,
+    r2_project_prefix: String,
+    capture_interval_secs: u64,
+    upload_max_retries: u32,
+    upload_retry_backoff_ms: u64,
+    keep_local_on_upload_failure: bool,
+    warmup_frames: u32,
+    warmup_frame_delay_ms: u64,
+    burst_count: u32,
+    overlay_timestamp: bool,
+    overlay_font_size: f32,
+    overlay_position: OverlayPosition,
+    max_output_width: u32,
+    max_output_height: u32,
+    thumbnail_width: u32,
+    embed_exif: bool,
+    write_sidecar: bool,
+    rotate_degrees: u32,
+    local_retention_count: usize,
+    jpeg_quality: u8,
+    auto_white_balance: bool,
+    r2_public_base_url: String,
+    webhook_url: String,
+    webhook_timeout_secs: u64,
+    metrics_port: u16,
+    healthcheck_file: String,
+    maintain_index: bool,
+    index_max_entries: usize,
+    motion_threshold: f64,
+    log_format: String,
+    log_level: String,
+    timelapse_fps: u32,
+    camera_init_retries: u32,
+    camera_init_retry_delay_ms: u64,
+    skip_local_save: bool,
+    s3_endpoint: String,
+    s3_region: String,
+    path_style: bool,
+    verify_upload: bool,
+    min_mean_brightness: f64,
+    filename_timestamp_format: String,
+    upload_enabled: bool,
+    min_free_disk_mb: u64,
+    frame_format: String,
+    gamma: f64,
+    key_layout: String,
+    max_concurrent_uploads: usize,
+    avif_quality: u8,
+    crop_mode: String,
+    capture_timeout_secs: u64,
+    backend: String,
+    webdav_url: String,
+    webdav_username: String,
+    webdav_password: String,
+    serve_port: u16,
+    rotate_fine_degrees: f32,
+    rotate_fill_color: [u8; 3],
+    sharpen_amount: f32,
+    sharpen_sigma: f32,
+    sharpen_threshold: i32,
+    frozen_frame_threshold: u32,
+    reopen_frozen_camera: bool,
+    upload_cache_control: String,
+    upload_content_disposition: String,
+    maintain_latest: bool,
+    latest_name: String,
+    flip_horizontal: bool,
+    flip_vertical: bool,
+    capture_jitter_secs: u64,
+    min_upload_interval_secs: u64,
+    camera_device_path: String,
+    recent_buffer_size: usize,
+    parallel_sharpness: bool,
+    pre_capture_command: String,
+    post_upload_command: String,
+    hook_timeout_secs: u64,
+    delete_after_upload: bool,
+    use_max_resolution: bool,
+    sensor_data_path: String,
+    sensor_overlay_position: OverlayPosition,
+    hash_in_key: bool,
+    skip_existing_hash: bool,
+    crop_enabled: bool,
+    schedule_start: String,
+    schedule_end: String,
+    schedule_interval_secs: u64,
+    watermark_path: String,
+    watermark_position: OverlayPosition,
+    watermark_opacity: f32,
+    stream_reconnect_max_backoff_ms: u64,
+    output_aspect_ratio: String,
+    letterbox_color: [u8; 3],
+    source_type: String,
+    rtsp_url: String,
+    target_brightness_min: f64,
+    target_brightness_max: f64,
+    skip_outside_brightness_target: bool,
+    crop_regions: Vec<CropRegion>,
+    upload_full_frame: bool,
+    wait_for_cameras: bool,
+    log_histogram: bool,
+    gif_frame_delay_ms: u32,
+    embed_srgb_profile: bool,
+    pipeline: Vec<String>,
+    startup_delay_secs: u64,
+    encryption_key: String,
+    upload_max_bytes_per_sec: u64,
+    usage_report_interval_secs: u64,
+    timezone: String,
+    obstruction_detection: bool,
+    obstruction_stddev_threshold: f64,
+    obstruction_webhook: bool,
+    daily_collage: bool,
+    collage_tile_width: u32,
+    destinations: Vec<DestinationConfig>,
+    cameras: Vec<CameraConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            camera_id: "".to_string(),
+            camera_width: 640,
+            camera_height: 480,
+            camera_frame_rate: 30,
+            output_dir: "pictures".to_string(),
+            output_prefix: "".to_string(),
+            output_format: "jpeg".to_string(),
+            crop_x: 0,
+            crop_y: 0,
+            crop_width: 640,
+            crop_height: 480,
+            no_default_camera: true,
+            r2_accound_id: "".to_string(),
+            r2_bucket_name: "".to_string(),
+            r2_access_key_id: "".to_string(),
+            r2_secret_access_key: "".to_string(),
+            r2_project_prefix: "plant-cam/".to_string(),
+            capture_interval_secs: 0,
+            upload_max_retries: 3,
+            upload_retry_backoff_ms: 1000,
+            keep_local_on_upload_failure: false,
+            warmup_frames: 0,
+            warmup_frame_delay_ms: 100,
+            burst_count: 1,
+            overlay_timestamp: false,
+            overlay_font_size: 20.0,
+            overlay_position: OverlayPosition::BottomLeft,
+            max_output_width: 0,
+            max_output_height: 0,
+            thumbnail_width: 0,
+            embed_exif: false,
+            write_sidecar: false,
+            rotate_degrees: 0,
+            local_retention_count: 0,
+            jpeg_quality: 75,
+            auto_white_balance: false,
+            r2_public_base_url: "".to_string(),
+            webhook_url: "".to_string(),
+            webhook_timeout_secs: 10,
+            metrics_port: 0,
+            healthcheck_file: "".to_string(),
+            maintain_index: false,
+            index_max_entries: 1000,
+            motion_threshold: 0.0,
+            log_format: "text".to_string(),
+            log_level: "info".to_string(),
+            timelapse_fps: 24,
+            camera_init_retries: 3,
+            camera_init_retry_delay_ms: 2000,
+            skip_local_save: false,
+            s3_endpoint: "".to_string(),
+            s3_region: "us-east-1".to_string(),
+            path_style: false,
+            verify_upload: false,
+            min_mean_brightness: 0.0,
+            filename_timestamp_format: "%Y%m%d_%H%M".to_string(),
+            upload_enabled: true,
+            min_free_disk_mb: 0,
+            frame_format: "MJPEG".to_string(),
+            gamma: 1.0,
+            key_layout: "flat".to_string(),
+            max_concurrent_uploads: 1,
+            avif_quality: 60,
+            crop_mode: "pixels".to_string(),
+            capture_timeout_secs: 30,
+            backend: "s3".to_string(),
+            webdav_url: "".to_string(),
+            webdav_username: "".to_string(),
+            webdav_password: "".to_string(),
+            serve_port: 0,
+            rotate_fine_degrees: 0.0,
+            rotate_fill_color: [0, 0, 0],
+            sharpen_amount: 0.0,
+            sharpen_sigma: 1.0,
+            sharpen_threshold: 0,
+            frozen_frame_threshold: 0,
+            reopen_frozen_camera: false,
+            upload_cache_control: "".to_string(),
+            upload_content_disposition: "".to_string(),
+            maintain_latest: false,
+            latest_name: "latest".to_string(),
+            flip_horizontal: false,
+            flip_vertical: false,
+            capture_jitter_secs: 0,
+            min_upload_interval_secs: 0,
+            camera_device_path: "".to_string(),
+            recent_buffer_size: 0,
+            parallel_sharpness: true,
+            pre_capture_command: "".to_string(),
+            post_upload_command: "".to_string(),
+            hook_timeout_secs: 30,
+            delete_after_upload: false,
+            use_max_resolution: false,
+            sensor_data_path: "".to_string(),
+            sensor_overlay_position: OverlayPosition::TopLeft,
+            hash_in_key: false,
+            skip_existing_hash: false,
+            crop_enabled: true,
+            schedule_start: "".to_string(),
+            schedule_end: "".to_string(),
+            schedule_interval_secs: 0,
+            watermark_path: "".to_string(),
+            watermark_position: OverlayPosition::BottomRight,
+            watermark_opacity: 1.0,
+            stream_reconnect_max_backoff_ms: 30000,
+            output_aspect_ratio: "".to_string(),
+            letterbox_color: [0, 0, 0],
+            source_type: "usb".to_string(),
+            rtsp_url: "".to_string(),
+            target_brightness_min: 0.0,
+            target_brightness_max: 0.0,
+            skip_outside_brightness_target: false,
+            crop_regions: Vec::new(),
+            upload_full_frame: false,
+            wait_for_cameras: false,
+            log_histogram: false,
+            gif_frame_delay_ms: 200,
+            embed_srgb_profile: false,
+            pipeline: Vec::new(),
+            startup_delay_secs: 0,
+            encryption_key: "".to_string(),
+            upload_max_bytes_per_sec: 0,
+            usage_report_interval_secs: 0,
+            timezone: "".to_string(),
+            obstruction_detection: false,
+            obstruction_stddev_threshold: 5.0,
+            obstruction_webhook: false,
+            daily_collage: false,
+            collage_tile_width: 320,
+            destinations: Vec::new(),
+            cameras: Vec::new(),
+        }
+    }
+}
+
+/// Per-camera settings. When `cameras` is empty the top-level fields describe the only camera.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct CameraConfig {
+    camera_id: String,
+    camera_device_path: String,
+    source_type: String,
+    rtsp_url: String,
+    crop_x: u32,
+    crop_y: u32,
+    crop_width: u32,
+    crop_height: u32,
+    output_prefix: String,
+    /// Named regions each saved as its own picture from a single frame, replacing the crop above.
+    crop_regions: Vec<CropRegion>,
+    /// This camera's own upload destinations; when empty the top-level ones are used.
+    destinations: Vec<DestinationConfig>,
+}
+
+/// A crop saved as a separate picture, named by appending `name` to the output prefix.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct CropRegion {
+    name: String,
+    crop_x: u32,
+    crop_y: u32,
+    crop_width: u32,
+    crop_height: u32,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        let config = Config::default();
+        CameraConfig {
+            camera_id: config.camera_id,
+            camera_device_path: config.camera_device_path,
+            source_type: config.source_type,
+            rtsp_url: config.rtsp_url,
+            crop_x: config.crop_x,
+            crop_y: config.crop_y,
+            crop_width: config.crop_width,
+            crop_height: config.crop_height,
+            output_prefix: config.output_prefix,
+            crop_regions: config.crop_regions,
+            destinations: Vec::new(),
+        }
+    }
+}
+
+impl CameraConfig {
+    fn is_usb(&self) -> bool {
+        !self.source_type.eq_ignore_ascii_case("rtsp")
+    }
+
+    /// One config per picture taken from each frame, carrying that picture's crop and prefix.
+    fn regions(&self) -> Vec<CameraConfig> {
+        if self.crop_regions.is_empty() {
+            return vec![self.clone()];
+        }
+        self.crop_regions
+            .iter()
+            .map(|region| CameraConfig {
+                crop_x: region.crop_x,
+                crop_y: region.crop_y,
+                crop_width: region.crop_width,
+                crop_height: region.crop_height,
+                output_prefix: if self.output_prefix.is_empty() {
+                    region.name.clone()
+                } else {
+                    format!("{}-{}", self.output_prefix, region.name)
+                },
+                crop_regions: Vec::new(),
+                ..self.clone()
+            })
+            .collect()
+    }
+}
+
+/// Per-destination upload settings. When `destinations` is empty the top-level fields describe
+/// the only destination.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+struct DestinationConfig {
+    /// Shown in logs; defaults to the bucket name or WebDAV URL.
+    name: String,
+    backend: String,
+    r2_accound_id: String,
+    r2_bucket_name: String,
+    r2_access_key_id: String,
+    r2_secret_access_key: String,
+    s3_endpoint: String,
+    s3_region: String,
+    path_style: bool,
+    webdav_url: String,
+    webdav_username: String,
+    webdav_password: String,
+}
+
+impl Default for DestinationConfig {
+    fn default() -> Self {
+        Config::default().top_level_destination()
+    }
+}
+
+impl DestinationConfig {
+    fn label(&self) -> String {
+        if !self.name.is_empty() {
+            self.name.clone()
+        } else if self.backend.eq_ignore_ascii_case("webdav") {
+            self.webdav_url.clone()
+        } else {
+            self.r2_bucket_name.clone()
+        }
+    }
+}
+
+impl Config {
+    fn top_level_destination(&self) -> DestinationConfig {
+        DestinationConfig {
+            name: "".to_string(),
+            backend: self.backend.clone(),
+            r2_accound_id: self.r2_accound_id.clone(),
+            r2_bucket_name: self.r2_bucket_name.clone(),
+            r2_access_key_id: self.r2_access_key_id.clone(),
+            r2_secret_access_key: self.r2_secret_access_key.clone(),
+            s3_endpoint: self.s3_endpoint.clone(),
+            s3_region: self.s3_region.clone(),
+            path_style: self.path_style,
+            webdav_url: self.webdav_url.clone(),
+            webdav_username: self.webdav_username.clone(),
+            webdav_password: self.webdav_password.clone(),
+        }
+    }
+
+    fn destination_configs(&self) -> Vec<DestinationConfig> {
+        if !self.destinations.is_empty() {
+            return self.destinations.clone();
+        }
+        vec![self.top_level_destination()]
+    }
+
+    fn camera_configs(&self) -> Vec<CameraConfig> {
+        if !self.cameras.is_empty() {
+            return self.cameras.clone();
+        }
+        vec![CameraConfig {
+            camera_id: self.camera_id.clone(),
+            camera_device_path: self.camera_device_path.clone(),
+            source_type: self.source_type.clone(),
+            rtsp_url: self.rtsp_url.clone(),
+            crop_x: self.crop_x,
+            crop_y: self.crop_y,
+            crop_width: self.crop_width,
+            crop_height: self.crop_height,
+            output_prefix: self.output_prefix.clone(),
+            crop_regions: self.crop_regions.clone(),
+            destinations: Vec::new(),
+        }]
+    }
+
+    /// A copy safe to log, with secrets blanked out.
+    fn redacted(&self) -> Config {
+        let mut config = self.clone();
+        if !config.r2_secret_access_key.is_empty() {
+            config.r2_secret_access_key = "<redacted>".to_string();
+        }
+        if !config.webdav_password.is_empty() {
+            config.webdav_password = "<redacted>".to_string();
+        }
+        if !config.encryption_key.is_empty() {
+            config.encryption_key = "<redacted>".to_string();
+        }
+        config.rtsp_url = redact_url_credentials(&config.rtsp_url);
+        for camera in config.cameras.iter_mut() {
+            camera.rtsp_url = redact_url_credentials(&camera.rtsp_url);
+        }
+        let camera_destinations = config.cameras.iter_mut().flat_map(|camera| camera.destinations.iter_mut());
+        for destination in config.destinations.iter_mut().chain(camera_destinations) {
+            for secret in [&mut destination.r2_secret_access_key, &mut destination.webdav_password] {
+                if !secret.is_empty() {
+                    *secret = "<redacted>".to_string();
+                }
+            }
+        }
+        config
+    }
+}
 
-#[tokio::main]
-async fn main() {
-    simple_logger::init_with_level(log::Level::Info).unwrap();
-    let config = get_config();
+/// Blanks out the `user:password@` part of a URL, if any.
+fn redact_url_credentials(url: &str) -> String {
+    match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let authority = rest.split('/').next().unwrap_or("");
+            match authority.rfind('@') {
+                Some(at) => format!("{}://<redacted>@{}", scheme, &rest[at + 1..]),
+                None => url.to_string(),
+            }
+        }
+        None => url.to_string(),
+    }
+}
 
-    let cameras = get_cameras();
+impl Config {
+    /// Startup checks for settings that would otherwise only fail mid-capture. Returns every
+    /// problem found, so they can all be fixed at once.
+    fn validate(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        self.validate_camera(&mut problems);
+        self.validate_output(&mut problems);
+        self.validate_uploads(&mut problems);
+        for camera_config in self.camera_configs() {
+            self.validate_camera_config(&camera_config, &mut problems);
+        }
+        self.validate_output_prefixes(&mut problems);
+        if problems.is_empty() {
+            return Ok(());
+        }
+        Err(problems)
+    }
 
-    let camera_index = get_camera_index(&config, &cameras);
+    fn validate_camera(&self, problems: &mut Vec<String>) {
+        if !self.use_max_resolution && (self.camera_width == 0 || self.camera_height == 0) {
+            problems.push(format!("camera_width and camera_height must be positive, got {}x{}", self.camera_width, self.camera_height));
+        }
+        if self.camera_frame_rate == 0 {
+            problems.push("camera_frame_rate must be positive".to_string());
+        }
+        if let Err(e) = get_frame_format(self) {
+            problems.push(format!("{:#}", e));
+        }
+        if self.frame_format.eq_ignore_ascii_case("RAWRGB") {
+            warn!("RAWRGB frames are not supported by the camera backend, using uncompressed YUYV instead.");
+        }
+        if let Err(e) = get_schedule(self) {
+            problems.push(format!("{:#}", e));
+        }
+        if !["pixels", "percent"].contains(&self.crop_mode.to_lowercase().as_str()) {
+            problems.push(format!("crop_mode must be \"pixels\" or \"percent\", got {:?}", self.crop_mode));
+        }
+        if self.target_brightness_min > 0.0 && self.target_brightness_max > 0.0 && self.target_brightness_min > self.target_brightness_max {
+            problems.push(format!(
+                "target_brightness_min {} must not exceed target_brightness_max {}",
+                self.target_brightness_min, self.target_brightness_max,
+            ));
+        }
+    }
 
-    let mut camera = get_camera(camera_index, &config);
+    fn validate_output(&self, problems: &mut Vec<String>) {
+        if ![0, 90, 180, 270].contains(&self.rotate_degrees) {
+            problems.push(format!("rotate_degrees must be 0, 90, 180 or 270, got {}", self.rotate_degrees));
+        }
+        let timestamp_is_valid = !StrftimeItems::new(&self.filename_timestamp_format).any(|item| item == Item::Error);
+        if !timestamp_is_valid || Local::now().format(&self.filename_timestamp_format).to_string().is_empty() {
+            problems.push(format!("filename_timestamp_format {:?} is invalid or empty", self.filename_timestamp_format));
+        } else if Local::now().format(&self.filename_timestamp_format).to_string().contains(['/', '\\']) {
+            // Captures in subdirectories would be missed by retention, disk space checks and collages.
+            problems.push(format!("filename_timestamp_format {:?} must not produce path separators", self.filename_timestamp_format));
+        }
+        if let Err(e) = get_output_format(self) {
+            problems.push(format!("{:#}", e));
+        }
+        if let Err(e) = parse_aspect_ratio(&self.output_aspect_ratio) {
+            problems.push(format!("{:#}", e));
+        }
+        if let Err(e) = get_timezone(self) {
+            problems.push(format!("{:#}", e));
+        }
+        // Every frame is identical to itself, so a threshold of 1 would flag each capture.
+        if self.frozen_frame_threshold == 1 {
+            problems.push("frozen_frame_threshold must be 0 or at least 2".to_string());
+        }
+        if self.collage_tile_width == 0 {
+            problems.push("collage_tile_width must be non-zero".to_string());
+        }
+        // The collage is made from the day's local copies.
+        if self.daily_collage && (self.skip_local_save || self.delete_after_upload) {
+            problems.push("daily_collage needs local copies and cannot be combined with skip_local_save or delete_after_upload".to_string());
+        }
+        if !(1..=100).contains(&self.jpeg_quality) {
+            problems.push(format!("jpeg_quality must be between 1 and 100, got {}", self.jpeg_quality));
+        }
+        if !(1..=100).contains(&self.avif_quality) {
+            problems.push(format!("avif_quality must be between 1 and 100, got {}", self.avif_quality));
+        }
+        if self.sharpen_amount > 0.0 && self.sharpen_sigma <= 0.0 {
+            problems.push(format!("sharpen_sigma must be positive, got {}", self.sharpen_sigma));
+        }
+        if !(0.0..=1.0).contains(&self.watermark_opacity) {
+            problems.push(format!("watermark_opacity must be between 0 and 1, got {}", self.watermark_opacity));
+        }
+        if self.gamma <= 0.0 || !self.gamma.is_finite() {
+            problems.push(format!("gamma must be a positive number, got {}", self.gamma));
+        }
+        for name in &self.pipeline {
+            if find_image_op(name).is_none() {
+                let names: Vec<&str> = IMAGE_OPS.iter().map(|(name, _)| *name).collect();
+                problems.push(format!("Unknown pipeline operation {:?}, expected one of {}", name, names.join(", ")));
+            }
+        }
+    }
 
-    camera.open_stream().expect("Failed to open stream");
-    let frame = camera.frame().expect("Failed to get frame");
+    fn validate_uploads(&self, problems: &mut Vec<String>) {
+        if self.maintain_latest && (self.latest_name.is_empty() || self.latest_name.contains('/')) {
+            problems.push(format!("latest_name must be a non-empty file name without slashes, got {:?}", self.latest_name));
+        }
+        if !["flat", "date"].contains(&self.key_layout.to_lowercase().as_str()) {
+            problems.push(format!("key_layout must be \"flat\" or \"date\", got {:?}", self.key_layout));
+        }
+        if !self.encryption_key.is_empty() {
+            if let Err(e) = crypto::parse_key(&self.encryption_key) {
+                problems.push(format!("{:#}", e));
+            }
+        }
+        if !self.upload_enabled {
+            return;
+        }
+        let camera_destinations = self.camera_configs().into_iter().flat_map(|camera_config| camera_config.destinations);
+        for destination in self.destination_configs().into_iter().chain(camera_destinations) {
+            if let Err(e) = destination.validate() {
+                problems.push(format!("Destination {:?}: {:#}", destination.label(), e));
+            }
+        }
+    }
 
-    let image = crop_imm(&frame, config.crop_x, config.crop_y, config.crop_width, config.crop_height).to_image();
+    /// Captures are told apart by prefix alone, in local filenames, object keys, retention and collages.
+    fn validate_output_prefixes(&self, problems: &mut Vec<String>) {
+        let camera_configs = self.camera_configs();
+        if camera_configs.len() > 1 && camera_configs.iter().any(|camera_config| camera_config.output_prefix.is_empty()) {
+            problems.push("Every camera needs an output_prefix when more than one is configured".to_string());
+        }
+        let mut camera_prefixes: Vec<&str> = camera_configs.iter().map(|camera_config| camera_config.output_prefix.as_str()).collect();
+        camera_prefixes.sort_unstable();
+        if camera_prefixes.windows(2).any(|pair| pair[0] == pair[1]) {
+            problems.push("Cameras need unique output_prefix values".to_string());
+            return;
+        }
+        let mut region_prefixes: Vec<String> = camera_configs.iter().flat_map(|camera_config| camera_config.regions()).map(|region| region.output_prefix).collect();
+        region_prefixes.sort_unstable();
+        if let Some(pair) = region_prefixes.windows(2).find(|pair| pair[0] == pair[1]) {
+            problems.push(format!("Crop regions need unique output prefixes across cameras, {:?} is used twice", pair[0]));
+        }
+    }
 
-    let output_path = get_output_path(&config);
-    if let Some(parent) = output_path.parent() {
-        fs::create_dir_all(parent).expect(&format!("Could not create directory {:?}", parent));
+    fn validate_camera_config(&self, camera_config: &CameraConfig, problems: &mut Vec<String>) {
+        let mut names: Vec<&str> = camera_config.crop_regions.iter().map(|region| region.name.as_str()).collect();
+        if names.iter().any(|name| name.is_empty() || name.contains('/')) {
+            problems.push(format!("Crop regions of camera {:?} need non-empty names without slashes", camera_config.camera_id));
+        }
+        names.sort_unstable();
+        if names.windows(2).any(|pair| pair[0] == pair[1]) {
+            problems.push(format!("Crop regions of camera {:?} need unique names", camera_config.camera_id));
+        }
+        match camera_config.source_type.to_lowercase().as_str() {
+            "usb" => {}
+            "rtsp" if camera_config.rtsp_url.is_empty() => {
+                problems.push(format!("rtsp_url must be set for RTSP camera {:?}", camera_config.camera_id));
+            }
+            "rtsp" => {}
+            other => problems.push(format!("source_type must be \"usb\" or \"rtsp\", got {:?}", other)),
+        }
+        if !self.crop_enabled {
+            return;
+        }
+        for region in camera_config.regions() {
+            let crop = [region.crop_x, region.crop_y, region.crop_width, region.crop_height];
+            if region.crop_width == 0 || region.crop_height == 0 {
+                problems.push(format!("crop_width and crop_height must be non-zero, got {:?} for camera {:?}", crop, region.camera_id));
+            }
+            if self.crop_mode.eq_ignore_ascii_case("percent") && crop.iter().any(|percent| *percent > 100) {
+                problems.push(format!("Crop percentages must be at most 100, got {:?} for camera {:?}", crop, region.camera_id));
+            }
+        }
     }
-    image.save_with_format(&output_path, ImageFormat::Jpeg).expect("Failed to save picture");
+}
 
-    info!("Updating image.");
-    let mut image_file = File::open(&output_path).expect("Failed to open file for upload");
-    let mut image_file_buffer = Vec::new();
-    image_file.read_to_end(&mut image_file_buffer).expect("Failed to read file for upload");
-    let bucket = get_bucket(&config);
-    bucket.put_object_with_content_type(
-        format!("{}pictures/{}", config.r2_project_prefix, output_path.file_name().unwrap().to_str().unwrap()),
-        &image_file_buffer,
-        "image/jpeg",
-    ).await.expect("Failed to upload picture");
+impl DestinationConfig {
+    /// Checks the fields the destination's backend needs are filled in.
+    fn validate(&self) -> Result<()> {
+        let required: &[(&str, &str)] = match self.backend.to_lowercase().as_str() {
+            "s3" if self.s3_endpoint.is_empty() => &[
+                ("r2_accound_id", &self.r2_accound_id),
+                ("r2_bucket_name", &self.r2_bucket_name),
+                ("r2_access_key_id", &self.r2_access_key_id),
+                ("r2_secret_access_key", &self.r2_secret_access_key),
+            ],
+            "s3" => &[
+                ("r2_bucket_name", &self.r2_bucket_name),
+                ("r2_access_key_id", &self.r2_access_key_id),
+                ("r2_secret_access_key", &self.r2_secret_access_key),
+            ],
+            "webdav" => &[("webdav_url", &self.webdav_url)],
+            other => bail!("Unknown backend {:?}, expected \"s3\" or \"webdav\"", other),
+        };
+        let missing: Vec<&str> = required.iter().filter(|(_, value)| value.is_empty()).map(|(field, _)| *field).collect();
+        if !missing.is_empty() {
+            bail!("{} must be set", missing.join(", "));
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Config {
-    camera_id: String,
-    camera_width: u32,
-    camera_height: u32,
-    camera_frame_rate: u32,
-    output_dir: String,
-    output_prefix: String,
-    crop_x: u32,
-    crop_y: u32,
-    crop_width: u32,
-    crop_height: u32,
-    no_default_camera: bool,
-    r2_accound_id: String,
-    r2_bucket_name: String,
-    r2_access_key_id: String,
-    r2_secret_access_key: String,
-    r2_project_prefix: String,
+struct Args {
+    config_path: PathBuf,
+    dry_run: bool,
+    list_cameras: bool,
+    make_timelapse: Option<PathBuf>,
+    preview_crop: bool,
+    init_config: bool,
+    force: bool,
+    version: bool,
+    test_upload: bool,
+    once: bool,
+    decrypt: Option<PathBuf>,
+    make_collage: bool,
+    /// Set by `--quiet` or `--verbose`, overriding `log_level` and `RUST_LOG`.
+    log_level: Option<&'static str>,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            camera_id: "".to_string(),
-            camera_width: 640,
-            camera_height: 480,
-            camera_frame_rate: 30,
-            output_dir: "pictures".to_string(),
-            output_prefix: "".to_string(),
-            crop_x: 0,
-            crop_y: 0,
-            crop_width: 640,
-            crop_height: 480,
-            no_default_camera: true,
-            r2_accound_id: "".to_string(),
-            r2_bucket_name: "".to_string(),
-            r2_access_key_id: "".to_string(),
-            r2_secret_access_key: "".to_string(),
-            r2_project_prefix: "plant-cam/".to_string(),
+fn parse_args() -> Result<Args> {
+    let mut args = Args {
+        config_path: PathBuf::from("config.toml"),
+        dry_run: false,
+        list_cameras: false,
+        make_timelapse: None,
+        preview_crop: false,
+        init_config: false,
+        force: false,
+        version: false,
+        test_upload: false,
+        once: false,
+        decrypt: None,
+        make_collage: false,
+        log_level: None,
+    };
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--config" => {
+                args.config_path = PathBuf::from(argv.next().context("--config requires a path")?);
+            }
+            "--dry-run" => args.dry_run = true,
+            "--list-cameras" => args.list_cameras = true,
+            "--preview-crop" => args.preview_crop = true,
+            "--init-config" => args.init_config = true,
+            "--force" => args.force = true,
+            "--version" => args.version = true,
+            "--test-upload" => args.test_upload = true,
+            "--once" => args.once = true,
+            "--make-collage" => args.make_collage = true,
+            "--quiet" | "--verbose" => {
+                if args.log_level.is_some() {
+                    bail!("--quiet and --verbose can't be combined");
+                }
+                args.log_level = Some(if arg == "--quiet" { "error" } else { "debug" });
+            }
+            "--make-timelapse" => {
+                args.make_timelapse = Some(PathBuf::from(argv.next().context("--make-timelapse requires an output path")?));
+            }
+            "--decrypt" => {
+                args.decrypt = Some(PathBuf::from(argv.next().context("--decrypt requires a file")?));
+            }
+            other => bail!("Unknown argument {:?}", other),
         }
     }
+    Ok(args)
+}
+
+fn print_version() {
+    println!("plant_cam {} (commit {})", env!("CARGO_PKG_VERSION"), env!("PLANT_CAM_GIT_COMMIT"));
+    println!("nokhwa {}", env!("PLANT_CAM_NOKHWA_VERSION"));
+    println!("image {}", env!("PLANT_CAM_IMAGE_VERSION"));
+}
+
+/// Writes the default config, with a comment on each field, to `path` for new users to edit.
+fn init_config(path: &Path, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        bail!("{:?} already exists, pass --force to overwrite it", path);
+    }
+    save_file(path, config_template::render()?.as_bytes())?;
+    info!("Wrote default config to {:?}.", path);
+    Ok(())
+}
+
+fn get_config(path: &Path, log_level_override: Option<&str>) -> Result<Config> {
+    let cfg: Config = confy::load_path(path).with_context(|| format!("Error with config file {:?}", path))?;
+    let (cfg, overridden) = apply_env_overrides(cfg)?;
+    // --quiet and --verbose take precedence over RUST_LOG, which takes precedence over the config file.
+    let log_level = match log_level_override {
+        Some(log_level) => log_level.to_string(),
+        None => std::env::var("RUST_LOG").unwrap_or_else(|_| cfg.log_level.clone()),
+    };
+    logging::set_format(&cfg.log_format).and_then(|_| logging::set_level(&log_level)).map_err(|e| anyhow!(e))?;
+    info!("Loaded config from {:?}.", fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()));
+    if !overridden.is_empty() {
+        info!("Overrode config fields from environment: {}.", overridden.join(", "));
+    }
+    info!("{:?}", cfg.redacted());
+    Ok(cfg)
 }
 
-fn get_config() -> Config {
-    let cfg: Config = confy::load_path("config.toml").expect("Error with config file");
-    info!("{:?}", cfg);
-    cfg
+/// Overrides each config field with `PLANTCAM_<FIELD>` from the environment when set.
+/// String fields take the raw value; anything else is parsed as a TOML value.
+/// Returns the names of the overridden fields alongside the new config.
+fn apply_env_overrides(config: Config) -> Result<(Config, Vec<String>)> {
+    let mut table = match toml::Value::try_from(&config).context("Failed to serialise config")? {
+        toml::Value::Table(table) => table,
+        _ => unreachable!("config always serialises to a table"),
+    };
+    let mut overridden = Vec::new();
+    for (field, value) in table.iter_mut() {
+        let name = format!("{}{}", ENV_PREFIX, field.to_uppercase());
+        let raw = match std::env::var(&name) {
+            Ok(raw) => raw,
+            Err(_) => continue,
+        };
+        *value = match value {
+            toml::Value::String(_) => toml::Value::String(raw),
+            _ => toml::from_str::<toml::value::Table>(&format!("value = {}", raw))
+                .ok()
+                .and_then(|mut parsed| parsed.remove("value"))
+                .with_context(|| format!("Could not parse {} from the environment", name))?,
+        };
+        overridden.push(field.clone());
+    }
+    if overridden.is_empty() {
+        return Ok((config, overridden));
+    }
+    let config = toml::Value::Table(table).try_into().context("Invalid config value from environment")?;
+    Ok((config, overridden))
 }
 
-fn get_cameras() -> Vec<CameraInfo> {
-    let cameras = nokhwa::query_devices(nokhwa::CaptureAPIBackend::Auto).unwrap();
+fn get_cameras() -> Result<Vec<CameraInfo>> {
+    let cameras = nokhwa::query_devices(nokhwa::CaptureAPIBackend::Auto).context("Could not list cameras")?;
+    if cameras.is_empty() {
+        bail!("No cameras detected; check USB connection and permissions");
+    }
     info!("{} Cameras detected.", cameras.len());
-    cameras
+    Ok(cameras)
 }
 
-fn get_camera_index(config: &Config, cameras: &Vec<CameraInfo>) -> usize {
+/// Lists cameras like `get_cameras`, but in loop mode with `wait_for_cameras` keeps querying
+/// every cycle until one shows up, e.g. a camera plugged in after boot. Starts listening for
+/// shutdown into `shutdown` while waiting, and returns `None` if it arrives first.
+async fn wait_for_cameras(config: &Config, shutdown: &mut Option<watch::Receiver<bool>>) -> Result<Option<Vec<CameraInfo>>> {
+    let interval_secs = get_interval_secs(config, matches!(get_schedule(config), Ok(Some(_))));
+    loop {
+        match get_cameras() {
+            Err(e) if config.wait_for_cameras && config.capture_interval_secs > 0 => {
+                warn!("{:#}, checking again in {}s.", e, interval_secs);
+                let shutdown = shutdown.get_or_insert_with(listen_for_shutdown);
+                tokio::select! {
+                    _ = tokio::time::sleep(Duration::from_secs(interval_secs)) => {}
+                    _ = shutdown.changed() => return Ok(None),
+                }
+            }
+            result => return result.map(Some),
+        }
+    }
+}
+
+fn list_cameras() -> Result<()> {
+    let cameras = get_cameras()?;
+    println!("{:<6} {:<32} MISC", "INDEX", "NAME");
     for camera in cameras.iter() {
-        if camera.misc().to_lowercase().contains(&config.camera_id.to_lowercase()) {
-            info!("Using camera {} {}.", camera.index(), camera.human_name());
-            return camera.index();
+        println!("{:<6} {:<32} {}", camera.index(), camera.human_name(), camera.misc());
+        let formats = Camera::new(camera.index(), None).and_then(|mut device| device.compatible_camera_formats());
+        match formats {
+            Ok(formats) => {
+                for format in formats {
+                    println!("{:<6} {}x{} {} @ {}fps", "", format.width(), format.height(), format.format(), format.frame_rate());
+                }
+            }
+            Err(e) => println!("{:<6} Could not query formats: {}", "", e),
         }
     }
-    if config.no_default_camera {
-        error!("Could not find camera with id {}, exiting...", &config.camera_id);
-        panic!("Could not find camera with id {}", &config.camera_id);
+    Ok(())
+}
+
+/// The candidates in a comma-separated `camera_id`, in order of preference.
+fn camera_ids(camera_id: &str) -> Vec<&str> {
+    let candidates: Vec<&str> = camera_id.split(',').map(str::trim).filter(|id| !id.is_empty()).collect();
+    if candidates.is_empty() {
+        // An empty id matches any camera, so the first one is used.
+        return vec![""];
+    }
+    candidates
+}
+
+fn find_camera<'a>(camera_id: &str, cameras: &'a [CameraInfo]) -> Option<&'a CameraInfo> {
+    cameras.iter().find(|camera| camera.misc().to_lowercase().contains(&camera_id.to_lowercase()))
+}
+
+/// Finds the camera behind a V4L device node such as `/dev/video2`, following symlinks like
+/// `/dev/v4l/by-id/...`, so that identical cameras can be told apart.
+#[cfg(target_os = "linux")]
+fn get_camera_index_by_path(device_path: &str, cameras: &[CameraInfo]) -> Result<usize> {
+    let node = std::fs::canonicalize(device_path)
+        .with_context(|| format!("Could not resolve camera device {:?}", device_path))?;
+    let index: usize = node
+        .to_str()
+        .and_then(|node| node.strip_prefix("/dev/video"))
+        .and_then(|index| index.parse().ok())
+        .with_context(|| format!("{:?} is not a /dev/videoN device", node))?;
+    match cameras.iter().find(|camera| camera.index() == index) {
+        Some(camera) => {
+            info!("Using camera {} {} at {:?}.", camera.index(), camera.human_name(), node);
+            Ok(index)
+        }
+        None => bail!("No camera found at {:?}", node),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_camera_index_by_path(_device_path: &str, _cameras: &[CameraInfo]) -> Result<usize> {
+    bail!("camera_device_path is only supported on Linux")
+}
+
+fn get_frame_format(config: &Config) -> Result<FrameFormat> {
+    match config.frame_format.to_uppercase().as_str() {
+        "MJPEG" => Ok(FrameFormat::MJPEG),
+        "YUYV" => Ok(FrameFormat::YUYV),
+        // The camera backend has no raw RGB format, YUYV is the closest uncompressed one.
+        "RAWRGB" => Ok(FrameFormat::YUYV),
+        other => bail!("Unknown frame format {:?}, expected one of \"MJPEG\", \"YUYV\" or \"RAWRGB\"", other),
     }
-    warn!("Could not find camera with id {}, using camera with index 0.", &config.camera_id);
-    0
 }
 
-fn get_camera(index: usize, config: &Config) -> Camera {
-    let camera = Camera::new(
-        index,
-        Some(CameraFormat::new(Resolution::new(config.camera_width, config.camera_height), FrameFormat::MJPEG, config.camera_frame_rate))
-    ).expect("Failed to initialise camera");
+async fn get_camera(index: usize, config: &Config) -> Result<Camera> {
+    let frame_format = get_frame_format(config)?;
+    let requested = CameraFormat::new(Resolution::new(config.camera_width, config.camera_height), frame_format, config.camera_frame_rate);
+    let mut camera = retry_camera(config, "initialise camera", || Camera::new(index, None)).await?;
+    let format = match camera.compatible_camera_formats() {
+        Ok(formats) => match config.use_max_resolution.then(|| max_resolution_format(requested, &formats)).flatten() {
+            Some(format) => {
+                info!("Using the camera's highest resolution mode {}.", format);
+                format
+            }
+            None => {
+                if config.use_max_resolution {
+                    warn!("Camera reports no MJPEG modes, ignoring use_max_resolution.");
+                }
+                let format = choose_camera_format(requested, &formats).context("Camera reports no usable formats")?;
+                if format != requested {
+                    warn!("Camera does not support {}, using {} instead.", requested, format);
+                }
+                format
+            }
+        },
+        Err(e) => {
+            warn!("Could not query camera formats ({}), requesting {} as configured.", e, requested);
+            requested
+        }
+    };
+    camera.set_camera_format(format).context("Failed to initialise camera")?;
     info!("Camera format: {}.", camera.camera_format());
-    camera
+    Ok(camera)
+}
+
+/// Runs a camera operation, retrying transient failures such as a USB device that enumerates late.
+async fn retry_camera<T>(config: &Config, action: &str, mut operation: impl FnMut() -> Result<T, NokhwaError>) -> Result<T> {
+    let max_attempts = config.camera_init_retries + 1;
+    let mut attempt = 1;
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) if attempt < max_attempts => {
+                warn!(
+                    "Failed to {} (attempt {} of {}): {}, retrying in {}ms.",
+                    action, attempt, max_attempts, e, config.camera_init_retry_delay_ms,
+                );
+                tokio::time::sleep(Duration::from_millis(config.camera_init_retry_delay_ms)).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e).with_context(|| format!("Failed to {}", action)),
+        }
+    }
+}
+
+/// Picks `requested` if supported, else the smallest resolution at least as large (same
+/// frame format preferred, then the closest frame rate), else the largest available one.
+fn choose_camera_format(requested: CameraFormat, formats: &[CameraFormat]) -> Option<CameraFormat> {
+    if formats.contains(&requested) {
+        return Some(requested);
+    }
+    let same_format: Vec<CameraFormat> = formats.iter().filter(|format| format.format() == requested.format()).copied().collect();
+    let candidates = if same_format.is_empty() { formats.to_vec() } else { same_format };
+    let area = |format: &CameraFormat| format.width() as u64 * format.height() as u64;
+    let frame_rate_distance = |format: &CameraFormat| format.frame_rate().abs_diff(requested.frame_rate());
+    candidates
+        .iter()
+        .filter(|format| format.width() >= requested.width() && format.height() >= requested.height())
+        .min_by_key(|format| (area(format), frame_rate_distance(format)))
+        .or_else(|| candidates.iter().max_by_key(|format| (area(format), std::cmp::Reverse(frame_rate_distance(format)))))
+        .copied()
+}
+
+/// The highest resolution MJPEG mode, preferring the frame rate closest to `requested`'s.
+fn max_resolution_format(requested: CameraFormat, formats: &[CameraFormat]) -> Option<CameraFormat> {
+    let area = |format: &CameraFormat| format.width() as u64 * format.height() as u64;
+    let frame_rate_distance = |format: &CameraFormat| format.frame_rate().abs_diff(requested.frame_rate());
+    formats
+        .iter()
+        .filter(|format| format.format() == FrameFormat::MJPEG)
+        .max_by_key(|format| (area(format), std::cmp::Reverse(frame_rate_distance(format))))
+        .copied()
+}
+
+async fn warm_up_camera(config: &Config, camera: &mut Camera) -> Result<()> {
+    if config.warmup_frames == 0 {
+        return Ok(());
+    }
+    for _ in 0..config.warmup_frames {
+        camera.frame().context("Failed to get warmup frame")?;
+        tokio::time::sleep(Duration::from_millis(config.warmup_frame_delay_ms)).await;
+    }
+    info!("Discarded {} warmup frames.", config.warmup_frames);
+    Ok(())
+}
+
+/// Grabs a frame, giving up after `capture_timeout_secs` so a camera that stops responding
+/// can't hang the process. A camera that timed out is dropped, to be reopened next cycle.
+async fn grab_frame(config: &Config, camera: &mut Option<CameraHandle>) -> Result<RgbImage> {
+    let handle = camera.as_ref().context("Camera is not open")?;
+    let frame = if config.capture_timeout_secs == 0 {
+        handle.frame().await
+    } else {
+        match tokio::time::timeout(Duration::from_secs(config.capture_timeout_secs), handle.frame()).await {
+            Ok(frame) => frame,
+            Err(_) => {
+                *camera = None;
+                bail!("Timed out after {}s waiting for a frame", config.capture_timeout_secs)
+            }
+        }
+    };
+    if frame.is_err() {
+        // Usually the device went away, e.g. a bumped USB cable, so close it to have it reopened.
+        *camera = None;
+    }
+    frame
+}
+
+/// Grabs a frame cropped to `camera_config`'s crop, or the whole frame when `None`.
+async fn capture_cropped(config: &Config, camera_config: Option<&CameraConfig>, camera: &mut Option<CameraHandle>) -> Result<RgbImage> {
+    let frame = grab_frame(config, camera).await?;
+    match camera_config {
+        Some(camera_config) => crop_frame(config, camera_config, &frame),
+        None => Ok(frame),
+    }
+}
+
+fn crop_frame(config: &Config, camera_config: &CameraConfig, frame: &RgbImage) -> Result<RgbImage> {
+    if !config.crop_enabled {
+        return Ok(frame.clone());
+    }
+    let camera_config = &pixel_crop(config, camera_config, frame.width(), frame.height());
+    validate_crop(camera_config, frame.width(), frame.height())?;
+    Ok(crop_imm(frame, camera_config.crop_x, camera_config.crop_y, camera_config.crop_width, camera_config.crop_height).to_image())
+}
+
+/// The crop in pixels; in the percent crop mode the crop fields are percentages of the frame size.
+/// With cropping disabled it is the whole frame.
+fn pixel_crop(config: &Config, camera_config: &CameraConfig, frame_width: u32, frame_height: u32) -> CameraConfig {
+    let mut crop = camera_config.clone();
+    if !config.crop_enabled {
+        crop.crop_x = 0;
+        crop.crop_y = 0;
+        crop.crop_width = frame_width;
+        crop.crop_height = frame_height;
+    } else if config.crop_mode.eq_ignore_ascii_case("percent") {
+        let scale = |percent: u32, size: u32| (percent as u64 * size as u64 / 100) as u32;
+        crop.crop_x = scale(camera_config.crop_x, frame_width);
+        crop.crop_y = scale(camera_config.crop_y, frame_height);
+        crop.crop_width = scale(camera_config.crop_width, frame_width);
+        crop.crop_height = scale(camera_config.crop_height, frame_height);
+    }
+    crop
+}
+
+fn validate_crop(camera_config: &CameraConfig, frame_width: u32, frame_height: u32) -> Result<()> {
+    let problem = if camera_config.crop_width == 0 || camera_config.crop_height == 0 {
+        Some(format!("crop_width and crop_height must be non-zero, got {}x{}", camera_config.crop_width, camera_config.crop_height))
+    } else if camera_config.crop_x as u64 + camera_config.crop_width as u64 > frame_width as u64 {
+        Some(format!(
+            "crop_x + crop_width ({} + {}) exceeds the frame width {}",
+            camera_config.crop_x, camera_config.crop_width, frame_width,
+        ))
+    } else if camera_config.crop_y as u64 + camera_config.crop_height as u64 > frame_height as u64 {
+        Some(format!(
+            "crop_y + crop_height ({} + {}) exceeds the frame height {}",
+            camera_config.crop_y, camera_config.crop_height, frame_height,
+        ))
+    } else {
+        None
+    };
+    if let Some(problem) = problem {
+        bail!("Invalid crop: {} (frame is {}x{})", problem, frame_width, frame_height);
+    }
+    Ok(())
+}
+
+/// Grabs `burst_count` frames in a row, each cropped like `capture_cropped`.
+async fn capture_burst(config: &Config, camera_config: Option<&CameraConfig>, camera: &mut Option<CameraHandle>) -> Result<Vec<RgbImage>> {
+    let mut frames = Vec::with_capacity(config.burst_count as usize);
+    for _ in 0..config.burst_count {
+        frames.push(capture_cropped(config, camera_config, camera).await?);
+    }
+    Ok(frames)
+}
+
+/// Captures a burst cropped to `camera_config` and keeps its sharpest frame. Uncropped bursts
+/// are still scored on `scored_region`, so the published crop is what gets judged.
+async fn capture_sharpest(
+    config: &Config,
+    camera_config: Option<&CameraConfig>,
+    scored_region: &CameraConfig,
+    camera: &mut Option<CameraHandle>,
+) -> Result<RgbImage> {
+    let frames = capture_burst(config, camera_config, camera).await?;
+    let score_crop = match (camera_config, frames.first()) {
+        (None, Some(frame)) if config.crop_enabled => {
+            let crop = pixel_crop(config, scored_region, frame.width(), frame.height());
+            validate_crop(&crop, frame.width(), frame.height())?;
+            Some((crop.crop_x, crop.crop_y, crop.crop_width, crop.crop_height))
+        }
+        _ => None,
+    };
+    let started = Instant::now();
+    // Scoring is CPU-bound, so it runs off the async workers.
+    let parallel_sharpness = config.parallel_sharpness;
+    let (mut frames, scores) = tokio::task::spawn_blocking(move || {
+        let score = |frame: &RgbImage| match score_crop {
+            Some((x, y, width, height)) => sharpness(&crop_imm(frame, x, y, width, height).to_image()),
+            None => sharpness(frame),
+        };
+        let scores: Vec<f64> = if parallel_sharpness {
+            frames.par_iter().map(score).collect()
+        } else {
+            frames.iter().map(score).collect()
+        };
+        (frames, scores)
+    })
+    .await
+    .context("Sharpness scoring panicked")?;
+    let scoring_time = started.elapsed();
+    let (best, score) = scores
+        .into_iter()
+        .enumerate()
+        .fold((0, f64::MIN), |best, (i, score)| if score > best.1 { (i, score) } else { best });
+    info!("Picked sharpest of {} burst frames with score {:.2}, scoring took {:?}.", config.burst_count, score, scoring_time);
+    Ok(frames.swap_remove(best))
+}
+
+/// Variance of the Laplacian of the luma channel; higher means sharper.
+fn sharpness(image: &RgbImage) -> f64 {
+    let gray = grayscale(image);
+    let (width, height) = gray.dimensions();
+    if width < 3 || height < 3 {
+        return 0.0;
+    }
+    let luma = |x: u32, y: u32| gray.get_pixel(x, y)[0] as f64;
+    let mut sum = 0.0;
+    let mut sum_sq = 0.0;
+    for y in 1..height - 1 {
+        for x in 1..width - 1 {
+            let laplacian = luma(x - 1, y) + luma(x + 1, y) + luma(x, y - 1) + luma(x, y + 1) - 4.0 * luma(x, y);
+            sum += laplacian;
+            sum_sq += laplacian * laplacian;
+        }
+    }
+    let count = ((width - 2) * (height - 2)) as f64;
+    let mean = sum / count;
+    sum_sq / count - mean * mean
+}
+
+fn rotate(image: RgbImage, degrees: u32) -> RgbImage {
+    match degrees {
+        90 => rotate90(&image),
+        180 => rotate180(&image),
+        270 => rotate270(&image),
+        _ => image,
+    }
+}
+
+/// Mean luma in 0..=255.
+fn mean_brightness(image: &RgbImage) -> f64 {
+    let gray = grayscale(image);
+    let total: u64 = gray.as_raw().iter().map(|value| *value as u64).sum();
+    total as f64 / gray.as_raw().len().max(1) as f64
+}
+
+fn luminance_stddev(image: &RgbImage) -> f64 {
+    let gray = grayscale(image);
+    let count = gray.as_raw().len().max(1) as f64;
+    let mean = gray.as_raw().iter().map(|value| *value as f64).sum::<f64>() / count;
+    let variance = gray.as_raw().iter().map(|value| (*value as f64 - mean).powi(2)).sum::<f64>() / count;
+    variance.sqrt()
+}
+
+/// Mean absolute per-channel difference in 0..=255; frames of different sizes count as fully different.
+fn mean_absolute_difference(previous: &RgbImage, current: &RgbImage) -> f64 {
+    if previous.dimensions() != current.dimensions() {
+        return 255.0;
+    }
+    let total: u64 = previous
+        .as_raw()
+        .iter()
+        .zip(current.as_raw().iter())
+        .map(|(a, b)| a.abs_diff(*b) as u64)
+        .sum();
+    total as f64 / previous.as_raw().len().max(1) as f64
+}
+
+/// Downscales `image` to fit within the limits, preserving aspect ratio. A limit of 0 means unbounded.
+fn limit_size(image: RgbImage, max_width: u32, max_height: u32) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let width_ratio = if max_width > 0 { max_width as f64 / width as f64 } else { 1.0 };
+    let height_ratio = if max_height > 0 { max_height as f64 / height as f64 } else { 1.0 };
+    let ratio = width_ratio.min(height_ratio);
+    if ratio >= 1.0 {
+        return image;
+    }
+    let new_width = ((width as f64 * ratio).round() as u32).max(1);
+    let new_height = ((height as f64 * ratio).round() as u32).max(1);
+    info!("Resizing image from {}x{} to {}x{}.", width, height, new_width, new_height);
+    resize(&image, new_width, new_height, FilterType::Lanczos3)
 }
 
-fn get_output_path(config: &Config) -> PathBuf {
+/// Parses a ratio such as `"16:9"`, or `None` when empty.
+fn parse_aspect_ratio(ratio: &str) -> Result<Option<(u32, u32)>> {
+    if ratio.is_empty() {
+        return Ok(None);
+    }
+    let parsed = ratio
+        .split_once(':')
+        .and_then(|(width, height)| Some((width.trim().parse::<u32>().ok()?, height.trim().parse::<u32>().ok()?)))
+        .filter(|(width, height)| *width > 0 && *height > 0);
+    match parsed {
+        Some(parsed) => Ok(Some(parsed)),
+        None => bail!("output_aspect_ratio must look like \"16:9\", got {:?}", ratio),
+    }
+}
+
+/// Pads `image` with `color` bars to the given aspect ratio, keeping it centred and unscaled.
+fn letterbox(image: RgbImage, (ratio_width, ratio_height): (u32, u32), color: Rgb<u8>) -> RgbImage {
+    let (width, height) = image.dimensions();
+    let (new_width, new_height) = if width as u64 * ratio_height as u64 > height as u64 * ratio_width as u64 {
+        (width, (width as f64 * ratio_height as f64 / ratio_width as f64).round() as u32)
+    } else {
+        ((height as f64 * ratio_width as f64 / ratio_height as f64).round() as u32, height)
+    };
+    if (new_width, new_height) == (width, height) {
+        return image;
+    }
+    info!("Letterboxing image from {}x{} to {}x{}.", width, height, new_width, new_height);
+    let mut padded = RgbImage::from_pixel(new_width, new_height, color);
+    overlay(&mut padded, &image, (new_width - width) / 2, (new_height - height) / 2);
+    padded
+}
+
+/// Unsharp mask blended with the original by `amount`, where 1.0 is the full mask.
+fn sharpen(image: RgbImage, amount: f32, sigma: f32, threshold: i32) -> RgbImage {
+    info!("Sharpening with amount {:.2}, sigma {:.2} and threshold {}.", amount, sigma, threshold);
+    let mut sharpened = unsharpen(&image, sigma, threshold);
+    for (value, original) in sharpened.iter_mut().zip(image.iter()) {
+        let blended = *original as f32 + amount * (*value as f32 - *original as f32);
+        *value = blended.round().clamp(0.0, 255.0) as u8;
+    }
+    sharpened
+}
+
+/// Reads a flat JSON object such as `{"temperature": 21.5}` and formats it as `temperature: 21.5`,
+/// with entries separated by two spaces.
+fn read_sensor_data(path: &str) -> Result<String> {
+    let content = fs::read_to_string(path).with_context(|| format!("Could not read {:?}", path))?;
+    let data: serde_json::Value = serde_json::from_str(&content).with_context(|| format!("Could not parse {:?}", path))?;
+    let entries = data.as_object().with_context(|| format!("{:?} does not hold a JSON object", path))?;
+    Ok(entries
+        .iter()
+        .map(|(key, value)| match value {
+            serde_json::Value::String(value) => format!("{}: {}", key, value),
+            value => format!("{}: {}", key, value),
+        })
+        .collect::<Vec<_>>()
+        .join("  "))
+}
+
+fn get_output_format(config: &Config) -> Result<OutputFormat> {
+    match config.output_format.to_lowercase().as_str() {
+        "jpeg" => Ok(OutputFormat::Jpeg),
+        "png" => Ok(OutputFormat::Png),
+        "webp" => Ok(OutputFormat::WebP),
+        "avif" => Ok(OutputFormat::Avif),
+        "gif" => Ok(OutputFormat::Gif),
+        other => bail!("Unknown output format {:?}, expected one of \"jpeg\", \"png\", \"webp\", \"avif\" or \"gif\"", other),
+    }
+}
+
+fn get_output_path(config: &Config, output_prefix: &str, format: OutputFormat, captured_at: DateTime<Local>) -> PathBuf {
     let mut path = PathBuf::from(&config.output_dir);
-    let mut filename = format!("{}.jpg", Local::now().format("%Y%m%d_%H%M"));
-    if config.output_prefix != "" {
-        filename = format!("{}-{}", config.output_prefix, filename);
+    let mut filename = format!("{}.{}", plant_time(config, captured_at).format(&config.filename_timestamp_format), format.extension());
+    if !output_prefix.is_empty() {
+        filename = format!("{}-{}", output_prefix, filename);
     }
     path.push(filename);
     info!("Saving image to {:?}.", path);
     path
 }
 
-fn get_bucket(config: &Config) -> Bucket {
-    Bucket::new(
-        &config.r2_bucket_name,
-        Region::R2 { account_id: config.r2_accound_id.to_owned() },
-        Credentials::new(
-            Some(&config.r2_access_key_id),
-            Some(&config.r2_secret_access_key),
-            None, None, None,
-        ).expect("Could not initialise S3 credential"),
-    ).expect("Could not instantiate the existing bucket")
+/// Names a capture after the start of its SHA-256, e.g. `3f2a9c0d1b7e4a55.jpg`, so identical
+/// pictures map to the same object key.
+fn get_hashed_filename(output_prefix: &str, format: OutputFormat, content: &[u8]) -> PathBuf {
+    let hash: String = Sha256::digest(content).iter().take(8).map(|byte| format!("{:02x}", byte)).collect();
+    if output_prefix.is_empty() {
+        return PathBuf::from(format!("{}.{}", hash, format.extension()));
+    }
+    PathBuf::from(format!("{}-{}.{}", output_prefix, hash, format.extension()))
+}
+
+/// Places the thumbnail next to the main image, e.g. `20230101_1200.jpg` -> `20230101_1200-thumb.jpg`.
+fn get_thumbnail_path(output_path: &Path) -> PathBuf {
+    let stem = output_path.file_stem().unwrap().to_str().unwrap();
+    let extension = output_path.extension().unwrap().to_str().unwrap();
+    output_path.with_file_name(format!("{}-thumb.{}", stem, extension))
+}
+
+/// Places the uncropped frame next to the main image, e.g. `20230101_1200.jpg` -> `20230101_1200-full.jpg`.
+fn get_full_frame_path(output_path: &Path) -> PathBuf {
+    let stem = output_path.file_stem().unwrap().to_str().unwrap();
+    let extension = output_path.extension().unwrap().to_str().unwrap();
+    output_path.with_file_name(format!("{}-full.{}", stem, extension))
+}
+
+fn make_thumbnail(image: &RgbImage, width: u32) -> RgbImage {
+    let height = ((image.height() as u64 * width as u64) / image.width() as u64).max(1) as u32;
+    thumbnail(image, width, height)
+}
+
+/// `{prefix}{folder}/{filename}`, or `{prefix}{folder}/YYYY/MM/DD/{filename}` with the date key layout.
+fn get_object_key(config: &Config, folder: &str, path: &Path, captured_at: DateTime<Local>) -> String {
+    let filename = path.file_name().unwrap().to_str().unwrap();
+    if config.key_layout.eq_ignore_ascii_case("date") {
+        return format!("{}{}/{}/{}", config.r2_project_prefix, folder, plant_time(config, captured_at).format("%Y/%m/%d"), filename);
+    }
+    format!("{}{}/{}", config.r2_project_prefix, folder, filename)
+}
+
+/// Deletes the oldest captures in `output_dir` beyond `local_retention_count`, along with
+/// their thumbnails and sidecars. Files not named like our captures are left alone.
+fn apply_local_retention(config: &Config, output_prefix: &str, format: OutputFormat) {
+    let captures = list_captures(&config.output_dir, output_prefix, format);
+    if captures.len() <= config.local_retention_count {
+        return;
+    }
+    let excess = captures.len() - config.local_retention_count;
+    for path in captures.into_iter().take(excess) {
+        delete_capture(&path, "to stay within local retention");
+    }
+}
+
+/// Deletes a capture along with its thumbnail, full frame and sidecar, if present.
+fn delete_capture(path: &Path, reason: &str) {
+    for file in [get_thumbnail_path(path), get_full_frame_path(path), path.with_extension("json"), path.to_path_buf()] {
+        if !file.exists() {
+            continue;
+        }
+        match fs::remove_file(&file) {
+            Ok(()) => info!("Deleted {:?} {}.", file, reason),
+            Err(e) => warn!("Could not delete {:?}: {}.", file, e),
+        }
+    }
+}
+
+/// Checks that `output_dir` has at least `min_free_disk_mb` free, deleting the oldest captures
+/// to make room when retention is on. Returns whether there is room to save locally.
+fn ensure_free_disk_space(config: &Config, output_prefix: &str, format: OutputFormat) -> bool {
+    const MB: u64 = 1024 * 1024;
+    let required = config.min_free_disk_mb * MB;
+    let free_space = || {
+        fs::create_dir_all(&config.output_dir).ok();
+        fs2::available_space(&config.output_dir)
+    };
+    let mut free = match free_space() {
+        Ok(free) => free,
+        Err(e) => {
+            warn!("Could not check free space on {:?}: {}.", config.output_dir, e);
+            return true;
+        }
+    };
+    debug!("{} MB free on {:?}.", free / MB, config.output_dir);
+    if free >= required {
+        return true;
+    }
+    if config.local_retention_count > 0 {
+        for path in list_captures(&config.output_dir, output_prefix, format) {
+            delete_capture(&path, "to free disk space");
+            free = free_space().unwrap_or(0);
+            if free >= required {
+                return true;
+            }
+        }
+    }
+    warn!(
+        "Only {} MB free on {:?}, below min_free_disk_mb {}, skipping the local save.",
+        free / MB, config.output_dir, config.min_free_disk_mb,
+    );
+    false
+}
+
+/// Captures in `output_dir` named like ours, oldest first by modification time.
+fn list_captures(output_dir: &str, output_prefix: &str, format: OutputFormat) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(output_dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Could not scan {:?} for captures: {}.", output_dir, e);
+            return Vec::new();
+        }
+    };
+    let mut captures: Vec<(SystemTime, PathBuf)> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| is_capture_file(output_prefix, format, path))
+        .filter_map(|path| fs::metadata(&path).and_then(|metadata| metadata.modified()).ok().map(|modified| (modified, path)))
+        .collect();
+    captures.sort();
+    captures.into_iter().map(|(_, path)| path).collect()
+}
+
+fn is_capture_file(output_prefix: &str, format: OutputFormat, path: &Path) -> bool {
+    let (Some(stem), Some(extension)) = (path.file_stem().and_then(|s| s.to_str()), path.extension()) else {
+        return false;
+    };
+    extension == format.extension()
+        && !stem.ends_with("-thumb")
+        && !stem.ends_with("-full")
+        && (output_prefix.is_empty() || stem.starts_with(&format!("{}-", output_prefix)))
+}
+
+/// Joins `r2_public_base_url` with an object key, or `None` if no public base URL is configured.
+fn get_public_url(config: &Config, key: &str) -> Option<String> {
+    if config.r2_public_base_url.is_empty() {
+        return None;
+    }
+    Some(format!("{}/{}", config.r2_public_base_url.trim_end_matches('/'), key))
+}
+
+/// Rewrites the healthcheck file so external watchdogs can check its mtime.
+fn touch_healthcheck(path: &str) {
+    if let Err(e) = fs::write(path, format!("{}\n", Local::now().to_rfc3339())) {
+        warn!("Could not write healthcheck file {:?}: {}.", path, e);
+    }
+}
+
+fn encode_image(config: &Config, image: &RgbImage, format: OutputFormat) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    match format {
+        OutputFormat::Jpeg => {
+            JpegEncoder::new_with_quality(&mut buffer, config.jpeg_quality)
+                .encode_image(image)
+                .context("Failed to encode picture")?;
+        }
+        OutputFormat::Png => {
+            PngEncoder::new(&mut buffer)
+                .encode(image.as_raw(), image.width(), image.height(), ColorType::Rgb8)
+                .context("Failed to encode picture")?;
+        }
+        OutputFormat::WebP => {
+            let encoded = webp::Encoder::from_rgb(image, image.width(), image.height()).encode_lossless();
+            buffer.extend_from_slice(&encoded);
+        }
+        OutputFormat::Avif => {
+            // AV1 encoding takes seconds at full resolution, much slower than the others.
+            let started = Instant::now();
+            let pixels: Vec<RGB8> = image.pixels().map(|pixel| RGB8::new(pixel[0], pixel[1], pixel[2])).collect();
+            let encoded = AvifEncoder::new()
+                .with_quality(config.avif_quality as f32)
+                .with_speed(6)
+                .encode_rgb(Img::new(pixels.as_slice(), image.width() as usize, image.height() as usize))
+                .context("Failed to encode picture")?;
+            debug!("Encoded AVIF in {:?}.", started.elapsed());
+            buffer = encoded.avif_file;
+        }
+        OutputFormat::Gif => buffer = encode_gif(config, std::slice::from_ref(image))?,
+    }
+    if config.embed_srgb_profile {
+        match format {
+            OutputFormat::Jpeg => buffer = icc::embed_srgb_jpeg(&buffer)?,
+            OutputFormat::Png => buffer = icc::embed_srgb_png(&buffer)?,
+            _ => {}
+        }
+    }
+    Ok(buffer)
+}
+
+/// Encodes `frames` as a looping animated GIF, showing each for `gif_frame_delay_ms`.
+fn encode_gif(config: &Config, frames: &[RgbImage]) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    {
+        let mut encoder = GifEncoder::new(&mut buffer);
+        encoder.set_repeat(Repeat::Infinite).context("Failed to encode animation")?;
+        let delay = Delay::from_numer_denom_ms(config.gif_frame_delay_ms, 1);
+        let frames = frames.iter().map(|frame| Frame::from_parts(DynamicImage::ImageRgb8(frame.clone()).into_rgba8(), 0, 0, delay));
+        encoder.encode_frames(frames).context("Failed to encode animation")?;
+    }
+    Ok(buffer)
+}
+
+fn save_file(path: &Path, content: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("Could not create directory {:?}", parent))?;
+    }
+    fs::write(path, content).with_context(|| format!("Failed to save {:?}", path))
+}
+
+async fn upload_with_retry(config: &Config, destination: &Destination, key: &str, content: &[u8], content_type: &str) -> bool {
+    let max_attempts = config.upload_max_retries + 1;
+    let mut backoff = Duration::from_millis(config.upload_retry_backoff_ms);
+    for attempt in 1..=max_attempts {
+        match destination.uploader.upload(key, content, content_type).await {
+            Ok(()) => {
+                info!("Uploaded {} to {} after {} attempt(s).", key, destination.name, attempt);
+                metrics::record_uploaded_bytes(content.len());
+                return true;
+            }
+            Err(e) if attempt < max_attempts => {
+                warn!("Upload attempt {} of {} to {} failed: {:#}, retrying in {:?}.", attempt, max_attempts, destination.name, e, backoff);
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            Err(e) => {
+                error!("Upload to {} failed after {} attempt(s): {:#}.", destination.name, attempt, e);
+            }
+        }
+    }
+    false
+}
+
+/// Notifies `webhook_url` of a new upload or an alert. Failures are only logged so they never fail the capture.
+async fn send_webhook(config: &Config, payload: &WebhookPayload) {
+    let client = match reqwest::Client::builder().timeout(Duration::from_secs(config.webhook_timeout_secs)).build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Could not build webhook client: {}.", e);
+            return;
+        }
+    };
+    let response = client.post(&config.webhook_url).json(payload).send().await.and_then(|response| response.error_for_status());
+    match response {
+        Ok(_) => info!("Notified webhook {}.", config.webhook_url),
+        Err(e) => warn!("Webhook {} failed: {}.", config.webhook_url, e),
+    }
+}
+
+/// Appends `key` to the bucket's `index.json`, creating it if absent and dropping the oldest
+/// entries beyond `index_max_entries`.
+async fn update_index(config: &Config, destination: &Destination, key: &str, captured_at: DateTime<Local>, encryption_key: Option<&[u8; 32]>) {
+    // Encrypted like the captures it lists, as `index.json.enc`.
+    let index_key = match encryption_key {
+        Some(_) => format!("{}index.json.enc", config.r2_project_prefix),
+        None => format!("{}index.json", config.r2_project_prefix),
+    };
+    let downloaded = destination.uploader.download(&index_key).await.and_then(|content| match (content, encryption_key) {
+        (Some(content), Some(encryption_key)) => crypto::decrypt(encryption_key, &content).map(Some),
+        (content, _) => Ok(content),
+    });
+    let mut index = match downloaded {
+        Ok(Some(content)) => match serde_json::from_slice::<Index>(&content) {
+            Ok(index) => index,
+            Err(e) => {
+                warn!("Existing {} is not a valid index ({}), starting a new one.", index_key, e);
+                Index::default()
+            }
+        },
+        Ok(None) => {
+            info!("No {} uploaded yet, starting a new one.", index_key);
+            Index::default()
+        }
+        Err(e) => {
+            warn!("Could not fetch {}, leaving it unchanged: {:#}.", index_key, e);
+            return;
+        }
+    };
+    index.entries.push(IndexEntry {
+        key: key.to_string(),
+        timestamp: plant_time(config, captured_at).to_rfc3339(),
+    });
+    let excess = index.entries.len().saturating_sub(config.index_max_entries);
+    index.entries.drain(..excess);
+
+    let content = serde_json::to_vec(&index).unwrap();
+    let (index_key, content, content_type) = match encryption_key {
+        Some(encryption_key) => match crypto::encrypt(encryption_key, &content) {
+            Ok(encrypted) => (index_key, encrypted, "application/octet-stream"),
+            Err(e) => {
+                warn!("Could not encrypt {}, leaving it unchanged: {:#}.", index_key, e);
+                return;
+            }
+        },
+        None => (index_key, content, "application/json"),
+    };
+    if !upload_with_retry(config, destination, &index_key, &content, content_type).await {
+        warn!("Failed to update {}.", index_key);
+    }
 }
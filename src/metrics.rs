@@ -0,0 +1,89 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use log::{info, error};
+use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+
+static CAPTURES_TOTAL: AtomicU64 = AtomicU64::new(0);
+static UPLOAD_SUCCESSES_TOTAL: AtomicU64 = AtomicU64::new(0);
+static UPLOAD_FAILURES_TOTAL: AtomicU64 = AtomicU64::new(0);
+static LAST_SUCCESS_TIMESTAMP_SECONDS: AtomicU64 = AtomicU64::new(0);
+static UPLOADED_BYTES_TOTAL: AtomicU64 = AtomicU64::new(0);
+
+pub fn record_capture() {
+    CAPTURES_TOTAL.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_upload_success(timestamp: i64) {
+    UPLOAD_SUCCESSES_TOTAL.fetch_add(1, Ordering::Relaxed);
+    LAST_SUCCESS_TIMESTAMP_SECONDS.store(timestamp.max(0) as u64, Ordering::Relaxed);
+}
+
+pub fn record_upload_failure() {
+    UPLOAD_FAILURES_TOTAL.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Counts every object uploaded, including thumbnails, sidecars and the latest copy.
+pub fn record_uploaded_bytes(bytes: usize) {
+    UPLOADED_BYTES_TOTAL.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn captures() -> u64 {
+    CAPTURES_TOTAL.load(Ordering::Relaxed)
+}
+
+pub fn uploaded_bytes() -> u64 {
+    UPLOADED_BYTES_TOTAL.load(Ordering::Relaxed)
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+fn render() -> String {
+    let metrics = [
+        ("plantcam_captures_total", "counter", "Frames captured.", &CAPTURES_TOTAL),
+        ("plantcam_upload_successes_total", "counter", "Pictures uploaded, counted once per destination.", &UPLOAD_SUCCESSES_TOTAL),
+        ("plantcam_upload_failures_total", "counter", "Picture uploads that failed after all retries, counted once per destination.", &UPLOAD_FAILURES_TOTAL),
+        ("plantcam_uploaded_bytes_total", "counter", "Bytes uploaded across all destinations.", &UPLOADED_BYTES_TOTAL),
+        (
+            "plantcam_last_success_timestamp_seconds",
+            "gauge",
+            "Unix time of the last successful capture and upload.",
+            &LAST_SUCCESS_TIMESTAMP_SECONDS,
+        ),
+    ];
+    let mut output = String::new();
+    for (name, kind, help, value) in metrics {
+        output.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n{} {}\n", name, help, name, kind, name, value.load(Ordering::Relaxed)));
+    }
+    output
+}
+
+async fn handle(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = if request.uri().path() == "/metrics" {
+        Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(Body::from(render()))
+    } else {
+        Response::builder().status(StatusCode::NOT_FOUND).body(Body::empty())
+    };
+    Ok(response.unwrap())
+}
+
+/// Serves `/metrics` on `port` from a background task.
+pub fn spawn_server(port: u16) {
+    let address = SocketAddr::from(([0, 0, 0, 0], port));
+    tokio::spawn(async move {
+        let service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(handle)) });
+        let server = match Server::try_bind(&address) {
+            Ok(builder) => builder.serve(service),
+            Err(e) => {
+                error!("Could not start metrics server on {}: {}.", address, e);
+                return;
+            }
+        };
+        info!("Serving metrics on http://{}/metrics.", address);
+        if let Err(e) = server.await {
+            error!("Metrics server stopped: {}.", e);
+        }
+    });
+}
@@ -0,0 +1,90 @@
+use serde::{Serialize, Deserialize};
+use std::path::Path;
+use anyhow::{Context, Result};
+use image::{Rgb, RgbImage, RgbaImage};
+use imageproc::drawing::draw_text_mut;
+use rusttype::{point, Font, Scale};
+
+static FONT_DATA: &[u8] = include_bytes!("../assets/DejaVuSansMono.ttf");
+
+const PADDING: u32 = 4;
+const BACKGROUND_OPACITY: f32 = 0.6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverlayPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+/// Draws `text` in the given corner over a semi-transparent dark box.
+pub fn draw_text_overlay(image: &mut RgbImage, text: &str, font_size: f32, position: OverlayPosition) {
+    let font = Font::try_from_bytes(FONT_DATA).expect("Failed to load bundled font");
+    let scale = Scale::uniform(font_size);
+    let (text_width, text_height) = text_size(&font, scale, text);
+
+    let box_width = (text_width + 2 * PADDING).min(image.width());
+    let box_height = (text_height + 2 * PADDING).min(image.height());
+    let box_x = match position {
+        OverlayPosition::TopLeft | OverlayPosition::BottomLeft => 0,
+        OverlayPosition::TopRight | OverlayPosition::BottomRight => image.width() - box_width,
+    };
+    let box_y = match position {
+        OverlayPosition::TopLeft | OverlayPosition::TopRight => 0,
+        OverlayPosition::BottomLeft | OverlayPosition::BottomRight => image.height() - box_height,
+    };
+
+    for y in box_y..box_y + box_height {
+        for x in box_x..box_x + box_width {
+            let pixel = image.get_pixel_mut(x, y);
+            for channel in pixel.0.iter_mut() {
+                *channel = (*channel as f32 * (1.0 - BACKGROUND_OPACITY)) as u8;
+            }
+        }
+    }
+
+    draw_text_mut(image, Rgb([255, 255, 255]), box_x + PADDING, box_y + PADDING, scale, &font, text);
+}
+
+pub fn load_watermark(path: &Path) -> Result<RgbaImage> {
+    Ok(image::open(path).with_context(|| format!("Could not load watermark {:?}", path))?.to_rgba8())
+}
+
+/// Blends `watermark` into the given corner, using its alpha channel scaled by `opacity`.
+/// A watermark larger than the image is clipped.
+pub fn draw_watermark(image: &mut RgbImage, watermark: &RgbaImage, opacity: f32, position: OverlayPosition) {
+    let width = watermark.width().min(image.width().saturating_sub(PADDING));
+    let height = watermark.height().min(image.height().saturating_sub(PADDING));
+    let left = match position {
+        OverlayPosition::TopLeft | OverlayPosition::BottomLeft => PADDING.min(image.width() - width),
+        OverlayPosition::TopRight | OverlayPosition::BottomRight => image.width() - width - PADDING.min(image.width() - width),
+    };
+    let top = match position {
+        OverlayPosition::TopLeft | OverlayPosition::TopRight => PADDING.min(image.height() - height),
+        OverlayPosition::BottomLeft | OverlayPosition::BottomRight => image.height() - height - PADDING.min(image.height() - height),
+    };
+    for y in 0..height {
+        for x in 0..width {
+            let mark = watermark.get_pixel(x, y);
+            let alpha = mark[3] as f32 / 255.0 * opacity;
+            let pixel = image.get_pixel_mut(left + x, top + y);
+            for (channel, mark_channel) in pixel.0.iter_mut().zip(mark.0.iter()) {
+                *channel = (*channel as f32 * (1.0 - alpha) + *mark_channel as f32 * alpha).round() as u8;
+            }
+        }
+    }
+}
+
+fn text_size(font: &Font, scale: Scale, text: &str) -> (u32, u32) {
+    let v_metrics = font.v_metrics(scale);
+    let width = font
+        .layout(text, scale, point(0.0, v_metrics.ascent))
+        .filter_map(|glyph| glyph.pixel_bounding_box())
+        .map(|bounding_box| bounding_box.max.x)
+        .max()
+        .unwrap_or(0);
+    let height = v_metrics.ascent - v_metrics.descent;
+    (width.max(0) as u32, height.ceil() as u32)
+}
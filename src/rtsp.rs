@@ -0,0 +1,53 @@
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+use anyhow::{bail, Context, Result};
+use image::RgbImage;
+
+/// Grabs a single frame from an RTSP stream by having `ffmpeg` decode it to a PNG on stdout.
+/// An ffmpeg still running after `timeout` is killed, so a stalled stream can't hold the camera
+/// thread; a zero `timeout` waits indefinitely.
+pub fn read_frame(url: &str, timeout: Duration) -> Result<RgbImage> {
+    let mut child = Command::new("ffmpeg")
+        .args(["-loglevel", "error", "-rtsp_transport", "tcp", "-i"])
+        .arg(url)
+        .args(["-frames:v", "1", "-f", "image2pipe", "-vcodec", "png", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("Failed to start ffmpeg, is it installed?")?;
+    // Drained on their own threads so a full pipe can't stall ffmpeg while we wait on it.
+    let mut stdout_pipe = child.stdout.take().context("ffmpeg stdout was not captured")?;
+    let mut stderr_pipe = child.stderr.take().context("ffmpeg stderr was not captured")?;
+    let stdout = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        stdout_pipe.read_to_end(&mut buffer).map(|_| buffer)
+    });
+    let stderr = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        stderr_pipe.read_to_end(&mut buffer).ok();
+        buffer
+    });
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait().context("Failed to wait for ffmpeg")? {
+            break status;
+        }
+        if !timeout.is_zero() && Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            bail!("ffmpeg did not return a frame within {:?}", timeout);
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let stdout = stdout.join().expect("ffmpeg stdout reader panicked").context("Failed to read ffmpeg output")?;
+    let stderr = stderr.join().expect("ffmpeg stderr reader panicked");
+    if !status.success() {
+        bail!("ffmpeg exited with {}: {}", status, String::from_utf8_lossy(&stderr).trim());
+    }
+    let frame = image::load_from_memory(&stdout).context("Could not decode the frame from ffmpeg")?;
+    Ok(frame.to_rgb8())
+}
@@ -0,0 +1,40 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use anyhow::{bail, Context, Result};
+use log::info;
+
+/// Encodes `frames`, in order, into an H.264 video by piping them through `ffmpeg`.
+/// All frames are assumed to share the same dimensions.
+pub fn make_timelapse(frames: &[PathBuf], output: &Path, fps: u32) -> Result<()> {
+    if frames.is_empty() {
+        bail!("No captures found to make a timelapse from");
+    }
+    info!("Encoding {} frames into {:?} at {} fps.", frames.len(), output, fps);
+
+    let mut ffmpeg = Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-f", "image2pipe", "-framerate"])
+        .arg(fps.to_string())
+        .args(["-i", "-", "-c:v", "libx264", "-pix_fmt", "yuv420p"])
+        // libx264 with yuv420p needs even dimensions.
+        .args(["-vf", "pad=ceil(iw/2)*2:ceil(ih/2)*2"])
+        .arg(output)
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("Failed to start ffmpeg, is it installed?")?;
+
+    let mut stdin = ffmpeg.stdin.take().unwrap();
+    for frame in frames {
+        let bytes = fs::read(frame).with_context(|| format!("Failed to read {:?}", frame))?;
+        stdin.write_all(&bytes).context("Failed to write frame to ffmpeg")?;
+    }
+    drop(stdin);
+
+    let status = ffmpeg.wait().context("Failed to wait for ffmpeg")?;
+    if !status.success() {
+        bail!("ffmpeg exited with {}", status);
+    }
+    info!("Timelapse written to {:?}.", output);
+    Ok(())
+}
@@ -0,0 +1,302 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use awscreds::Credentials;
+use futures_util::StreamExt;
+use log::{debug, error};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{Body, Client, Method, RequestBuilder, StatusCode};
+use s3::Region;
+use s3::bucket::Bucket;
+use s3::error::S3Error;
+use crate::{Config, DestinationConfig};
+
+/// A storage backend captures are uploaded to, addressed by object key.
+#[async_trait]
+pub trait Uploader: Send + Sync {
+    async fn upload(&self, key: &str, content: &[u8], content_type: &str) -> Result<()>;
+
+    /// Fetches the object at `key`, or `None` if there is none.
+    async fn download(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Whether an object is stored at `key`.
+    async fn exists(&self, key: &str) -> Result<bool>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// An uploader and the name it is logged under.
+pub struct Destination {
+    pub name: String,
+    pub uploader: Arc<dyn Uploader>,
+}
+
+/// Builds an uploader for each of `destinations`.
+pub fn get_destinations(destinations: &[DestinationConfig], config: &Config) -> Result<Vec<Destination>> {
+    destinations
+        .iter()
+        .map(|destination| {
+            let uploader = get_uploader(destination, config)
+                .with_context(|| format!("Invalid destination {:?}", destination.label()))?;
+            Ok(Destination { name: destination.label(), uploader })
+        })
+        .collect()
+}
+
+/// Builds the uploader selected by `backend`.
+fn get_uploader(destination: &DestinationConfig, config: &Config) -> Result<Arc<dyn Uploader>> {
+    match destination.backend.to_lowercase().as_str() {
+        "s3" => Ok(Arc::new(S3Uploader::new(destination, config)?)),
+        "webdav" => Ok(Arc::new(WebdavUploader::new(destination, config)?)),
+        other => bail!("Unknown backend {:?}, expected \"s3\" or \"webdav\"", other),
+    }
+}
+
+/// Cloudflare R2, or any S3-compatible service such as MinIO when `s3_endpoint` is set.
+pub struct S3Uploader {
+    bucket: Bucket,
+    /// `bucket` with the configured upload headers, used only for uploads.
+    upload_bucket: Bucket,
+    /// The configured upload headers again, for throttled uploads through a presigned URL.
+    upload_headers: HeaderMap,
+    client: Client,
+    verify_upload: bool,
+    max_bytes_per_sec: u64,
+}
+
+impl S3Uploader {
+    pub fn new(destination: &DestinationConfig, config: &Config) -> Result<S3Uploader> {
+        let region = if destination.s3_endpoint.is_empty() {
+            Region::R2 { account_id: destination.r2_accound_id.to_owned() }
+        } else {
+            Region::Custom { region: destination.s3_region.to_owned(), endpoint: destination.s3_endpoint.to_owned() }
+        };
+        let mut bucket = Bucket::new(
+            &destination.r2_bucket_name,
+            region,
+            Credentials::new(
+                Some(&destination.r2_access_key_id),
+                Some(&destination.r2_secret_access_key),
+                None, None, None,
+            ).context("Could not initialise S3 credential")?,
+        ).context("Could not instantiate the existing bucket")?;
+        if destination.path_style {
+            bucket.set_path_style();
+        }
+        let mut upload_bucket = bucket.clone();
+        let mut upload_headers = HeaderMap::new();
+        for (name, value) in [("cache-control", &config.upload_cache_control), ("content-disposition", &config.upload_content_disposition)] {
+            if !value.is_empty() {
+                upload_bucket.add_header(name, value);
+                upload_headers.insert(name, HeaderValue::from_str(value).with_context(|| format!("Invalid {} header", name))?);
+            }
+        }
+        Ok(S3Uploader {
+            bucket,
+            upload_bucket,
+            upload_headers,
+            client: Client::builder().build().context("Could not build S3 client")?,
+            verify_upload: config.verify_upload,
+            max_bytes_per_sec: config.upload_max_bytes_per_sec,
+        })
+    }
+
+    /// PUTs through a presigned URL with our own client, as the bucket API has no way to pace the body.
+    async fn upload_throttled(&self, key: &str, content: &[u8], content_type: &str) -> Result<()> {
+        let mut headers = self.upload_headers.clone();
+        if let Some(capture_id) = crate::logging::capture_id() {
+            headers.insert("x-amz-meta-capture-id", HeaderValue::from_str(&capture_id)?);
+        }
+        let url = self.bucket.presign_put(key, 3600, Some(headers.clone()))?;
+        self.client
+            .put(url)
+            .headers(headers)
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, content.len())
+            .body(throttled_body(content, self.max_bytes_per_sec))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Uploader for S3Uploader {
+    /// A single upload tagged with the capture ID, optionally confirmed by comparing the stored object's size with ours.
+    async fn upload(&self, key: &str, content: &[u8], content_type: &str) -> Result<()> {
+        if self.max_bytes_per_sec > 0 {
+            self.upload_throttled(key, content, content_type).await?;
+        } else {
+            match crate::logging::capture_id() {
+                Some(capture_id) => {
+                    let mut bucket = self.upload_bucket.clone();
+                    bucket.add_header("x-amz-meta-capture-id", &capture_id);
+                    bucket.put_object_with_content_type(key, content, content_type).await?
+                }
+                None => self.upload_bucket.put_object_with_content_type(key, content, content_type).await?,
+            };
+        }
+        if !self.verify_upload {
+            return Ok(());
+        }
+        let (head, _) = self.bucket.head_object(key).await.context("could not verify upload")?;
+        match head.content_length {
+            Some(length) if length == content.len() as i64 => Ok(()),
+            length => {
+                error!("Uploaded {} but the bucket reports {:?} bytes instead of {}.", key, length, content.len());
+                bail!("uploaded object size does not match")
+            }
+        }
+    }
+
+    async fn download(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match self.bucket.get_object(key).await {
+            Ok(response) => Ok(Some(response.bytes().to_vec())),
+            Err(S3Error::Http(404, _)) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        match self.bucket.head_object(key).await {
+            Ok((_, status)) => Ok((200..300).contains(&status)),
+            Err(S3Error::Http(404, _)) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.bucket.delete_object(key).await?;
+        Ok(())
+    }
+}
+
+/// HTTP PUT to a WebDAV server such as Nextcloud, creating missing folders on the way.
+pub struct WebdavUploader {
+    client: Client,
+    base_url: String,
+    username: String,
+    password: String,
+    max_bytes_per_sec: u64,
+}
+
+impl WebdavUploader {
+    pub fn new(config: &DestinationConfig, global_config: &Config) -> Result<WebdavUploader> {
+        if config.webdav_url.is_empty() {
+            bail!("webdav_url must be set to use the webdav backend");
+        }
+        Ok(WebdavUploader {
+            client: Client::builder().build().context("Could not build WebDAV client")?,
+            base_url: config.webdav_url.trim_end_matches('/').to_string(),
+            username: config.webdav_username.clone(),
+            password: config.webdav_password.clone(),
+            max_bytes_per_sec: global_config.upload_max_bytes_per_sec,
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.client.request(method, format!("{}/{}", self.base_url, path));
+        if self.username.is_empty() {
+            return request;
+        }
+        request.basic_auth(&self.username, Some(&self.password))
+    }
+
+    /// Creates each folder leading up to `key`, as WebDAV refuses to PUT into a missing one.
+    async fn create_folders(&self, key: &str) -> Result<()> {
+        let mkcol = Method::from_bytes(b"MKCOL").unwrap();
+        let folders: Vec<&str> = key.split('/').collect();
+        for depth in 1..folders.len() {
+            let folder = folders[..depth].join("/");
+            let response = self.request(mkcol.clone(), &folder).send().await?;
+            // 405 means the folder already exists.
+            if response.status().is_success() {
+                debug!("Created WebDAV folder {}.", folder);
+            } else if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                return Err(anyhow!("could not create folder {}: {}", folder, response.status()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Uploader for WebdavUploader {
+    async fn upload(&self, key: &str, content: &[u8], content_type: &str) -> Result<()> {
+        let put = || {
+            let body = if self.max_bytes_per_sec > 0 { throttled_body(content, self.max_bytes_per_sec) } else { Body::from(content.to_vec()) };
+            self.request(Method::PUT, key)
+                .header(CONTENT_TYPE, content_type)
+                .header(CONTENT_LENGTH, content.len())
+                .body(body)
+                .send()
+        };
+        let mut response = put().await?;
+        if response.status() == StatusCode::CONFLICT {
+            self.create_folders(key).await?;
+            response = put().await?;
+        }
+        response.error_for_status()?;
+        Ok(())
+    }
+
+    async fn download(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let response = self.request(Method::GET, key).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.error_for_status()?.bytes().await?.to_vec()))
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let response = self.request(Method::HEAD, key).send().await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+        Ok(true)
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.request(Method::DELETE, key).send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+/// When the next throttled chunk may be sent, shared by every upload in flight.
+static NEXT_CHUNK_AT: Mutex<Option<tokio::time::Instant>> = Mutex::new(None);
+
+/// Feeds `content` out a tenth of `bytes_per_sec` at a time, ten chunks a second across all
+/// concurrent uploads, so together they can't saturate a slow uplink.
+fn throttled_body(content: &[u8], bytes_per_sec: u64) -> Body {
+    let chunk_size = (bytes_per_sec / 10).max(1) as usize;
+    let chunks: Vec<Vec<u8>> = content.chunks(chunk_size).map(<[u8]>::to_vec).collect();
+    let stream = futures_util::stream::iter(chunks).then(|chunk| async move {
+        let send_at = {
+            let mut next_chunk_at = NEXT_CHUNK_AT.lock().unwrap();
+            let send_at = next_chunk_at.map_or_else(tokio::time::Instant::now, |at| at.max(tokio::time::Instant::now()));
+            *next_chunk_at = Some(send_at + Duration::from_millis(100));
+            send_at
+        };
+        tokio::time::sleep_until(send_at).await;
+        Ok::<_, std::io::Error>(chunk)
+    });
+    Body::wrap_stream(stream)
+}
+
+/// Uploads a small marker object, reads it back and deletes it, to check a destination's
+/// settings and credentials without waiting for the first capture.
+pub async fn test_destination(destination: &Destination, key: &str) -> Result<()> {
+    let content = format!("plant_cam connection test {}\n", chrono::Local::now().to_rfc3339());
+    destination.uploader.upload(key, content.as_bytes(), "text/plain").await.context("Upload failed")?;
+    let downloaded = destination.uploader.download(key).await.context("Reading back failed")?;
+    let result = match downloaded {
+        Some(downloaded) if downloaded == content.as_bytes() => Ok(()),
+        Some(_) => Err(anyhow!("Read back different content than was uploaded")),
+        None => Err(anyhow!("Uploaded object could not be found")),
+    };
+    destination.uploader.delete(key).await.context("Deleting the test object failed")?;
+    result
+}